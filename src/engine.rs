@@ -1,28 +1,452 @@
 mod parser;
 mod codegen;
 mod evaluator;
+mod unicode_tables;
 
 use std::fmt::{Display, Formatter};
+use std::ops::{Range, RangeInclusive};
+use std::sync::Arc;
 use crate::helper::DynError;
+use evaluator::EvalError;
+use parser::Look;
+
+pub use evaluator::Engine;
+pub use parser::{escape, Syntax};
+
+/// キャプチャ位置を保持するスロット
+type Slots = Vec<Option<usize>>;
 
 #[derive(Debug, PartialEq)]
 pub enum Instruction {
     Char(char),
+    Class(Arc<[(char, char)]>, bool), // 昇順で重ならない範囲のいずれかに含まれる（否定の場合は含まれない）1文字
+    Any(bool),                        // 任意の1文字。偽の場合は改行を除く
+    Assert(Look),                     // 現在位置が条件を満たさなければ失敗
     Match,
     Jump(usize),
     Split(usize, usize),
+    Save(usize),
+    /// 後方参照。グループがマッチした文字列と同じ文字列を読む。真の場合は大文字と小文字を区別しない
+    Backref(usize, bool),
+    /// 先読み。否定かどうかと、表明を満たした場合に続けるアドレス
+    ///
+    /// 本体は次の命令から`SubMatch`まで。
+    LookAhead(bool, usize),
+    /// 後読み。`LookAhead`に加えて、本体がマッチする文字列の長さの範囲を持つ
+    LookBehind(bool, RangeInclusive<usize>, usize),
+    /// アトミックグループ。マッチした場合に続けるアドレス
+    ///
+    /// 本体は次の命令から`SubMatch`まで。本体の最初のマッチで確定し、中の分岐には戻らない。
+    Atomic(usize),
+    SubMatch, // 先読み、後読み、アトミックグループの本体のマッチ
 }
 
 impl Display for Instruction {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Instruction::Char(c) => write!(f, "char {}", c),
+            Instruction::Class(ranges, negated) => {
+                write!(f, "class [{}", if *negated { "^" } else { "" })?;
+                for (start, end) in ranges.iter() {
+                    if start == end {
+                        write!(f, "{start}")?;
+                    } else {
+                        write!(f, "{start}-{end}")?;
+                    }
+                }
+                write!(f, "]")
+            }
+            Instruction::Any(true) => write!(f, "any"),
+            Instruction::Any(false) => write!(f, "any except \\n"),
+            Instruction::Assert(look) => match look {
+                Look::StartText => write!(f, "assert start_text"),
+                Look::EndText => write!(f, "assert end_text"),
+                Look::StartLine => write!(f, "assert start_line"),
+                Look::EndLine => write!(f, "assert end_line"),
+                Look::WordBoundary => write!(f, "assert word_boundary"),
+                Look::NotWordBoundary => write!(f, "assert not_word_boundary"),
+                Look::WordBoundaryUnicode => write!(f, "assert word_boundary_unicode"),
+                Look::NotWordBoundaryUnicode => write!(f, "assert not_word_boundary_unicode"),
+            },
             Instruction::Match => write!(f, "match"),
             Instruction::Jump(addr) => write!(f, "jump {:>04}", addr),
             Instruction::Split(addr1, addr2) =>
                 write!(f, "split {:>04} {:>04}", addr1, addr2),
+            Instruction::Save(slot) => write!(f, "save {slot}"),
+            Instruction::Backref(n, false) => write!(f, "backref {n}"),
+            Instruction::Backref(n, true) => write!(f, "backref {n} case_insensitive"),
+            Instruction::LookAhead(negated, next) => {
+                let neg = if *negated { "negative " } else { "" };
+                write!(f, "{neg}lookahead {:>04}", next)
+            }
+            Instruction::LookBehind(negated, len, next) => {
+                let neg = if *negated { "negative " } else { "" };
+                write!(f, "{neg}lookbehind {}..={} {:>04}", len.start(), len.end(), next)
+            }
+            Instruction::Atomic(next) => write!(f, "atomic {:>04}", next),
+            Instruction::SubMatch => write!(f, "submatch"),
+        }
+    }
+}
+
+/// コンパイル済みの正規表現
+///
+/// パースとコード生成は`Regex::new`で一度だけ行い、命令列を保持して使い回す。
+#[derive(Debug)]
+pub struct Regex {
+    expr: String,
+    code: Vec<Instruction>,
+    nslots: usize,
+    names: Arc<[Option<String>]>, // グループ番号ごとの名前
+    config: evaluator::Config,
+}
+
+impl Regex {
+    /// 正規表現をパースし、既定の設定で命令列にコンパイル
+    pub fn new(expr: &str) -> Result<Regex, DynError> {
+        RegexBuilder::new(expr).build()
+    }
+
+    /// コンパイル元の正規表現
+    pub fn as_str(&self) -> &str {
+        &self.expr
+    }
+
+    /// マッチ全体を含むキャプチャグループの数
+    pub fn captures_len(&self) -> usize {
+        self.nslots / 2
+    }
+
+    /// 各キャプチャグループの名前を番号順に返すイテレータ
+    ///
+    /// 名前のないグループとグループ0は`None`を返す。
+    pub fn capture_names(&self) -> CaptureNames<'_> {
+        CaptureNames(self.names.iter())
+    }
+
+    /// 行のいずれかの位置でマッチするかを判定
+    pub fn is_match(&self, line: &str) -> Result<bool, DynError> {
+        Ok(self.find(line)?.is_some())
+    }
+
+    /// 最も左にあるマッチを探索
+    pub fn find<'t>(&self, line: &'t str) -> Result<Option<Match<'t>>, DynError> {
+        let input = Input::new(line);
+        let found = self.search(&input, 0)?;
+        Ok(found.and_then(|slots| Some(input.to_match(slots[0]?, slots[1]?))))
+    }
+
+    /// 最も左にあるマッチを探索し、各キャプチャグループの位置を返す
+    pub fn captures<'t>(&self, line: &'t str) -> Result<Option<Captures<'t>>, DynError> {
+        let input = Input::new(line);
+        let found = self.search(&input, 0)?;
+        Ok(found.map(|slots| Captures {
+            text: line,
+            names: self.names.clone(),
+            locs: slots.iter().map(|s| s.map(|i| input.offsets[i])).collect(),
+        }))
+    }
+
+    /// 重なり合わないすべてのマッチを左から順に返すイテレータ
+    pub fn find_iter<'r, 't>(&'r self, line: &'t str) -> Matches<'r, 't> {
+        Matches {
+            re: self,
+            input: Input::new(line),
+            pos: 0,
+            last_end: None,
+            done: false,
+        }
+    }
+
+    /// 文字単位の位置`start`以降を探索し、キャプチャ位置を返す
+    fn search(&self, input: &Input, start: usize) -> Result<Option<Slots>, EvalError> {
+        evaluator::search(&self.code, &input.chars, start, self.nslots, &self.config)
+    }
+}
+
+/// 設定を指定して`Regex`をコンパイルするためのビルダ
+///
+/// ```
+/// use reg::RegexBuilder;
+/// let re = RegexBuilder::new("a*b").max_stack_size(1 << 16).build().unwrap();
+/// assert!(re.is_match("aab").unwrap());
+/// ```
+#[derive(Debug, Clone)]
+pub struct RegexBuilder {
+    expr: String,
+    flags: parser::Flags,
+    size_limit: usize,
+    config: evaluator::Config,
+}
+
+impl RegexBuilder {
+    /// 既定の設定でビルダを作成
+    pub fn new(expr: &str) -> RegexBuilder {
+        RegexBuilder {
+            expr: expr.to_string(),
+            flags: parser::Flags::default(),
+            size_limit: codegen::DEFAULT_SIZE_LIMIT,
+            config: evaluator::Config::default(),
         }
     }
+
+    /// マッチングに使う評価器。既定は`Engine::Auto`
+    pub fn engine(&mut self, engine: Engine) -> &mut RegexBuilder {
+        self.config.engine = engine;
+        self
+    }
+
+    /// パターンの構文。既定は`Syntax::Standard`
+    ///
+    /// ```
+    /// use reg::{RegexBuilder, Syntax};
+    /// let re = RegexBuilder::new(r"\(ab\)\{2\}+").syntax(Syntax::PosixBasic).build().unwrap();
+    /// assert!(re.is_match("abab+").unwrap());
+    /// assert!(!re.is_match("ababab").unwrap());
+    /// ```
+    pub fn syntax(&mut self, syntax: Syntax) -> &mut RegexBuilder {
+        self.flags.syntax = syntax;
+        self
+    }
+
+    /// `.`が改行にもマッチするか。パターン中の`(?s)`でも有効にできる
+    pub fn dot_matches_new_line(&mut self, yes: bool) -> &mut RegexBuilder {
+        self.flags.dot_all = yes;
+        self
+    }
+
+    /// `^`と`$`が行頭と行末にマッチするか。パターン中の`(?m)`でも有効にできる
+    pub fn multi_line(&mut self, yes: bool) -> &mut RegexBuilder {
+        self.flags.multi_line = yes;
+        self
+    }
+
+    /// 大文字と小文字を区別せずにマッチするか。パターン中の`(?i)`でも有効にできる
+    ///
+    /// 文字と文字クラスはUnicodeの単純ケースフォールディングで同一視される文字にもマッチする。
+    pub fn case_insensitive(&mut self, yes: bool) -> &mut RegexBuilder {
+        self.flags.case_insensitive = yes;
+        self
+    }
+
+    /// パターン中の空白と`#`から行末までのコメントを無視するか。パターン中の`(?x)`でも有効にできる
+    pub fn ignore_whitespace(&mut self, yes: bool) -> &mut RegexBuilder {
+        self.flags.extended = yes;
+        self
+    }
+
+    /// `\d`, `\w`, `\s`がUnicodeの定義に従うか。パターン中の`(?u)`でも有効にできる
+    ///
+    /// 無効の場合はASCIIの数字、英数字と`_`、空白文字のみを対象とする。
+    pub fn unicode(&mut self, yes: bool) -> &mut RegexBuilder {
+        self.flags.unicode = yes;
+        self
+    }
+
+    /// コンパイル後の命令数の上限
+    ///
+    /// `a{1000}{1000}`のような回数指定の繰り返しの展開で上限を超えた場合、`build`はエラーを返す。
+    /// 文字クラスの範囲は命令の間で共有するため、命令列のメモリ使用量は命令数にほぼ比例する。
+    /// 探索時に確保する領域（`Engine::Bounded`の訪問済み集合など）は入力の長さにも比例し、この上限では制限しない。
+    pub fn size_limit(&mut self, size: usize) -> &mut RegexBuilder {
+        self.size_limit = size;
+        self
+    }
+
+    /// 深さ優先探索で使うスタック長の上限
+    ///
+    /// 上限を超えた場合、マッチングはエラーを返す。
+    pub fn max_stack_size(&mut self, size: usize) -> &mut RegexBuilder {
+        self.config.max_stack_size = size;
+        self
+    }
+
+    /// 正規表現をパースし、命令列にコンパイル
+    pub fn build(&self) -> Result<Regex, DynError> {
+        let ast = parser::parse_with_flags(&self.expr, self.flags)?;
+        let code = codegen::get_code_with_limit(&ast, self.size_limit)?;
+        // `(a){0}`のように命令を生成しないグループもスロットを割り当てる
+        let nslots = (codegen::max_capture(&ast) + 1) * 2;
+        let mut config = self.config;
+        config.engine = evaluator::resolve_engine(&code, config.engine)?;
+        let names = codegen::capture_names(&ast);
+        Ok(Regex {
+            expr: self.expr.clone(),
+            code,
+            nslots,
+            names: names.into(),
+            config,
+        })
+    }
+}
+
+impl Display for Regex {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.expr)
+    }
+}
+
+/// マッチング対象の文字列
+///
+/// 評価器は文字単位で動作するため、文字のインデックスからバイト位置への対応も保持する。
+struct Input<'t> {
+    text: &'t str,
+    chars: Vec<char>,
+    offsets: Vec<usize>,
+}
+
+impl<'t> Input<'t> {
+    fn new(text: &'t str) -> Input<'t> {
+        let (offsets, chars) = text.char_indices().unzip();
+        let mut input = Input {
+            text,
+            chars,
+            offsets,
+        };
+        input.offsets.push(text.len());
+        input
+    }
+
+    fn to_match(&self, start: usize, end: usize) -> Match<'t> {
+        Match {
+            text: self.text,
+            start: self.offsets[start],
+            end: self.offsets[end],
+        }
+    }
+}
+
+/// マッチした部分文字列
+///
+/// 位置は元の文字列におけるバイト単位のオフセット。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match<'t> {
+    text: &'t str,
+    start: usize,
+    end: usize,
+}
+
+impl<'t> Match<'t> {
+    /// マッチの開始位置
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// マッチの終了位置
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// マッチの範囲
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    /// マッチした部分文字列
+    pub fn as_str(&self) -> &'t str {
+        &self.text[self.range()]
+    }
+}
+
+/// キャプチャグループごとのマッチ位置
+///
+/// グループ0はマッチ全体を表す。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Captures<'t> {
+    text: &'t str,
+    locs: Vec<Option<usize>>,
+    names: Arc<[Option<String>]>,
+}
+
+impl<'t> Captures<'t> {
+    /// i番目のグループのマッチ。グループがマッチに関与していない場合は`None`
+    pub fn get(&self, i: usize) -> Option<Match<'t>> {
+        let slot = i.checked_mul(2)?;
+        let start = (*self.locs.get(slot)?)?;
+        let end = (*self.locs.get(slot + 1)?)?;
+        Some(Match {
+            text: self.text,
+            start,
+            end,
+        })
+    }
+
+    /// 名前付きグループのマッチ。該当する名前のグループがない場合も`None`
+    pub fn name(&self, name: &str) -> Option<Match<'t>> {
+        let i = self.names.iter().position(|n| n.as_deref() == Some(name))?;
+        self.get(i)
+    }
+
+    /// マッチ全体を含むグループの数
+    pub fn len(&self) -> usize {
+        self.locs.len() / 2
+    }
+
+    /// 常に`false`。グループ0が必ず存在するため
+    pub fn is_empty(&self) -> bool {
+        self.locs.is_empty()
+    }
+
+    /// 各グループのマッチを番号順に返すイテレータ
+    pub fn iter(&self) -> impl Iterator<Item = Option<Match<'t>>> + '_ {
+        (0..self.len()).map(|i| self.get(i))
+    }
+}
+
+/// `Regex::capture_names`が返すイテレータ
+pub struct CaptureNames<'r>(std::slice::Iter<'r, Option<String>>);
+
+impl<'r> Iterator for CaptureNames<'r> {
+    type Item = Option<&'r str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|name| name.as_deref())
+    }
+}
+
+/// `Regex::find_iter`が返すイテレータ
+pub struct Matches<'r, 't> {
+    re: &'r Regex,
+    input: Input<'t>,
+    pos: usize,
+    last_end: Option<usize>,
+    done: bool,
+}
+
+impl<'r, 't> Iterator for Matches<'r, 't> {
+    type Item = Result<Match<'t>, DynError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done && self.pos <= self.input.chars.len() {
+            let (start, end) = match self.re.search(&self.input, self.pos) {
+                Ok(Some(slots)) => match (slots[0], slots[1]) {
+                    (Some(start), Some(end)) => (start, end),
+                    _ => break,
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+            };
+
+            if start == end {
+                // 空マッチの後は1文字進めて無限ループを防ぐ
+                self.pos = end + 1;
+                if self.last_end == Some(end) {
+                    // 直前のマッチに隣接する空マッチは報告しない
+                    continue;
+                }
+            } else {
+                self.pos = end;
+            }
+            self.last_end = Some(end);
+            return Some(Ok(self.input.to_match(start, end)));
+        }
+
+        self.done = true;
+        None
+    }
 }
 
 pub fn do_matching(expr: &str, line: &str, is_depth: bool) -> Result<bool, DynError> {
@@ -49,7 +473,7 @@ pub fn print(expr: &str) -> Result<(), DynError> {
 
 #[cfg(test)]
 mod tests {
-    use crate::engine::do_matching;
+    use crate::engine::{do_matching, Engine, Regex, RegexBuilder, Syntax};
 
     #[test]
     fn test_matching() {
@@ -60,7 +484,607 @@ mod tests {
 
         assert!(do_matching("abc|def", "def", true).unwrap());
         assert!(do_matching("a*", "aa", true).unwrap());
-        // assert!(do_matching("(abc)*", "abcabc", true).unwrap());
-        // assert!(do_matching("(ab|cd)+", "abcdcd", true).unwrap());
+        assert!(do_matching("(abc)*", "abcabc", true).unwrap());
+        assert!(do_matching("(ab|cd)+", "abcdcd", true).unwrap());
+    }
+
+    #[test]
+    fn test_class() {
+        for engine in [Engine::DepthFirst, Engine::BreadthFirst, Engine::Bounded] {
+            let re = RegexBuilder::new("[a-z_][a-z0-9_]*")
+                .engine(engine)
+                .build()
+                .unwrap();
+            let found = re
+                .find_iter("x1 = foo_bar + 42;")
+                .map(|m| m.unwrap().as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(found, vec!["x1", "foo_bar"]);
+
+            let re = RegexBuilder::new("[^ ]+").engine(engine).build().unwrap();
+            let found = re
+                .find_iter("あい う")
+                .map(|m| m.unwrap().as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(found, vec!["あい", "う"]);
+        }
+    }
+
+    #[test]
+    fn test_dot() {
+        let re = Regex::new("a.c").unwrap();
+        assert!(re.is_match("abc").unwrap());
+        assert!(re.is_match("aあc").unwrap());
+        assert!(!re.is_match("a\nc").unwrap());
+        assert!(!re.is_match("ac").unwrap());
+
+        let re = Regex::new("(?s)a.c").unwrap();
+        assert!(re.is_match("a\nc").unwrap());
+
+        let re = RegexBuilder::new("a.c").dot_matches_new_line(true).build().unwrap();
+        assert!(re.is_match("a\nc").unwrap());
+
+        let re = Regex::new(r"a\.c").unwrap();
+        assert!(re.is_match("a.c").unwrap());
+        assert!(!re.is_match("abc").unwrap());
+    }
+
+    #[test]
+    fn test_anchor() {
+        for engine in [Engine::DepthFirst, Engine::BreadthFirst, Engine::Bounded] {
+            let re = RegexBuilder::new("^abc$").engine(engine).build().unwrap();
+            assert!(re.is_match("abc").unwrap());
+            assert!(!re.is_match("abcd").unwrap());
+            assert!(!re.is_match("xabc").unwrap());
+            assert!(!re.is_match("x\nabc").unwrap());
+
+            let re = RegexBuilder::new("^[a-z]+$")
+                .engine(engine)
+                .multi_line(true)
+                .build()
+                .unwrap();
+            let found = re
+                .find_iter("foo\nBar\nbaz")
+                .map(|m| m.unwrap().as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(found, vec!["foo", "baz"]);
+
+            let re = RegexBuilder::new(r"(?m)\Aa|b\z|^c$")
+                .engine(engine)
+                .build()
+                .unwrap();
+            let found = re
+                .find_iter("ab\na\nc\nb")
+                .map(|m| m.unwrap().range())
+                .collect::<Vec<_>>();
+            assert_eq!(found, vec![0..1, 5..6, 7..8]);
+
+            let re = RegexBuilder::new("x*$").engine(engine).build().unwrap();
+            assert_eq!(re.find("abxx").unwrap().unwrap().range(), 2..4);
+        }
+    }
+
+    #[test]
+    fn test_repeat() {
+        for engine in [Engine::DepthFirst, Engine::BreadthFirst, Engine::Bounded] {
+            let re = RegexBuilder::new("^[0-9]{4}-[0-9]{2,}-([0-9]{1,2})$")
+                .engine(engine)
+                .build()
+                .unwrap();
+            let caps = re.captures("2024-010-7").unwrap().unwrap();
+            assert_eq!(caps.get(1).unwrap().as_str(), "7");
+            assert!(re.is_match("2024-01-31").unwrap());
+            assert!(!re.is_match("202-01-01").unwrap());
+            assert!(!re.is_match("2024-1-01").unwrap());
+            assert!(!re.is_match("2024-01-123").unwrap());
+
+            let re = RegexBuilder::new("(a|b){2,3}").engine(engine).build().unwrap();
+            let found = re
+                .find_iter("ababab a ab")
+                .map(|m| m.unwrap().as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(found, vec!["aba", "bab", "ab"]);
+        }
+
+        assert!(Regex::new("a{1000}{1000}{1000}").is_err());
+        let re = RegexBuilder::new("a{100}").size_limit(100).build();
+        assert!(re.is_err());
+        let re = RegexBuilder::new("a{100}").size_limit(101).build();
+        assert!(re.is_ok());
+    }
+
+    #[test]
+    fn test_lazy() {
+        let tests = [
+            (r#""(.*)""#, r#"x "a" "b" y"#, r#"a" "b"#),
+            (r#""(.*?)""#, r#"x "a" "b" y"#, "a"),
+            ("<(.+?)>", "<<a>>", "<a"),
+            ("a(b??)", "abb", ""),
+            ("a(b{1,3}?)", "abbb", "b"),
+            ("a(b{1,3}?)c", "abbbc", "bbb"),
+            ("(a*?)$", "aaa", "aaa"),
+        ];
+        for engine in [Engine::DepthFirst, Engine::BreadthFirst, Engine::Bounded] {
+            for (expr, line, group) in tests {
+                let re = RegexBuilder::new(expr).engine(engine).build().unwrap();
+                let caps = re.captures(line).unwrap().unwrap();
+                assert_eq!(caps.get(1).unwrap().as_str(), group, "{expr} {engine:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_perl_class() {
+        let re = Regex::new(r"(\d+)-(\w+)\s\S").unwrap();
+        let caps = re.captures("tel: 012-abc_9 x").unwrap().unwrap();
+        assert_eq!(caps.get(1).unwrap().as_str(), "012");
+        assert_eq!(caps.get(2).unwrap().as_str(), "abc_9");
+
+        let re = Regex::new(r"^[\D\s]+$").unwrap();
+        assert!(re.is_match("ab c").unwrap());
+        assert!(!re.is_match("a1").unwrap());
+
+        // ASCIIのみの定義では全角数字や仮名は対象外
+        assert!(!Regex::new(r"^\d+$").unwrap().is_match("１２３").unwrap());
+        assert!(Regex::new(r"^\W+$").unwrap().is_match("あいう").unwrap());
+        for engine in [Engine::DepthFirst, Engine::BreadthFirst, Engine::Bounded] {
+            let re = RegexBuilder::new(r"^\d+\s\w+$").engine(engine).unicode(true).build().unwrap();
+            assert!(re.is_match("１２３\u{3000}あいう").unwrap());
+            assert!(!re.is_match("１２３ あ-う").unwrap());
+        }
+        assert!(Regex::new(r"(?u)^[^\W\d]+$").unwrap().is_match("éあ_").unwrap());
+        assert!(!Regex::new(r"(?u)^[^\W\d]+$").unwrap().is_match("é1").unwrap());
+    }
+
+    #[test]
+    fn test_char_escape() {
+        let re = Regex::new(r"([^\t]*)\t([^\t\r\n]*)\r?\n").unwrap();
+        let caps = re.captures("id\tname\r\n").unwrap().unwrap();
+        assert_eq!(caps.get(1).unwrap().as_str(), "id");
+        assert_eq!(caps.get(2).unwrap().as_str(), "name");
+
+        let re = Regex::new(r"\x41\u{3042}[\u{1F600}-\u{1F64F}]").unwrap();
+        assert!(re.is_match("Aあ😊").unwrap());
+        assert!(Regex::new(r"\u{110000}").is_err());
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        for engine in [Engine::DepthFirst, Engine::BreadthFirst, Engine::Bounded] {
+            let re = RegexBuilder::new("k[a-c]+σ").engine(engine).case_insensitive(true).build().unwrap();
+            assert!(re.is_match("KaBcΣ").unwrap());
+            assert!(re.is_match("\u{212a}ACς").unwrap());
+            assert!(!re.is_match("kdσ").unwrap());
+        }
+
+        let re = Regex::new("a((?i)b)c").unwrap();
+        assert!(re.is_match("aBc").unwrap());
+        assert!(!re.is_match("aBC").unwrap());
+        assert!(!Regex::new("(?i)[^a-z]").unwrap().is_match("Q").unwrap());
+
+        // クラス内で否定された要素もクラス外と同じ文字にマッチする
+        let pairs = [
+            (r"(?i)[\W]", r"(?i)\W"),
+            (r"(?i)[\P{Lu}]", r"(?i)\P{Lu}"),
+            (r"(?i)[\S]", r"(?i)\S"),
+            (r"(?i)[^\w]", r"(?i)\W"),
+        ];
+        for (expr1, expr2) in pairs {
+            let (re1, re2) = (Regex::new(expr1).unwrap(), Regex::new(expr2).unwrap());
+            for c in ["k", "K", "\u{212a}", "a", "A", "s", "\u{17f}", "σ", "Σ", "1", "_", " "] {
+                assert_eq!(re1.is_match(c).unwrap(), re2.is_match(c).unwrap(), "{expr1} {c}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_inline_flags() {
+        let re = Regex::new("(?i)a(?-i:b)(?s:.)c(?m:$)").unwrap();
+        assert!(re.is_match("Ab\nC").unwrap());
+        assert!(!re.is_match("AB\nC").unwrap());
+
+        let re = RegexBuilder::new(
+            r"(\d{4}) - (\d{2})  # 年と月
+              (?-x: 日)         # 空白を含む",
+        )
+        .ignore_whitespace(true)
+        .build()
+        .unwrap();
+        let caps = re.captures("2024-05 日").unwrap().unwrap();
+        assert_eq!(caps.len(), 3);
+        assert_eq!(caps.get(2).unwrap().as_str(), "05");
+        assert!(Regex::new("(?q:a)").is_err());
+    }
+
+    #[test]
+    fn test_named_group() {
+        let re = Regex::new(r"(?P<year>\d{4})-(?:(?<month>\d{2})|(\w+))").unwrap();
+        assert_eq!(
+            re.capture_names().collect::<Vec<_>>(),
+            vec![None, Some("year"), Some("month"), None]
+        );
+
+        let caps = re.captures("on 2024-05").unwrap().unwrap();
+        assert_eq!(caps.name("year").unwrap().range(), 3..7);
+        assert_eq!(caps.name("month").unwrap().as_str(), "05");
+        assert_eq!(caps.get(3), None);
+        assert_eq!(caps.name("day"), None);
+
+        let caps = re.captures("2024-may").unwrap().unwrap();
+        assert_eq!(caps.name("month"), None);
+        assert_eq!(caps.get(3).unwrap().as_str(), "may");
+        assert!(Regex::new("(?P<a>x)(?P<a>y)").is_err());
+    }
+
+    #[test]
+    fn test_word_boundary() {
+        for engine in [Engine::DepthFirst, Engine::BreadthFirst, Engine::Bounded] {
+            let re = RegexBuilder::new(r"\berror\b").engine(engine).build().unwrap();
+            let found = re.find_iter("errors: error, error_x error").collect::<Result<Vec<_>, _>>();
+            let ranges = found.unwrap().iter().map(|m| m.range()).collect::<Vec<_>>();
+            assert_eq!(ranges, vec![8..13, 23..28]);
+
+            let re = RegexBuilder::new(r"\Bor\B").engine(engine).build().unwrap();
+            assert_eq!(re.find("or errors").unwrap().unwrap().range(), 6..8);
+        }
+
+        // ASCIIのみの定義では非ASCII文字は単語を構成しない
+        assert!(Regex::new(r"\bé").unwrap().is_match("café").unwrap());
+        assert!(!Regex::new(r"(?u)\bé").unwrap().is_match("café").unwrap());
+        assert!(Regex::new(r"(?u)\bcafé\b").unwrap().is_match("un café.").unwrap());
+        assert!(Regex::new(r"^\B$").unwrap().is_match("").unwrap());
+    }
+
+    #[test]
+    fn test_backref() {
+        let re = Regex::new(r"\b(\w+) \1\b").unwrap();
+        assert_eq!(re.find("it is is ok").unwrap().unwrap().as_str(), "is is");
+        assert!(!re.is_match("it is isn't").unwrap());
+
+        let re = Regex::new(r#"(?<q>['"])(.*?)\k<q>"#).unwrap();
+        let caps = re.captures(r#"say "it's" ok"#).unwrap().unwrap();
+        assert_eq!(caps.get(2).unwrap().as_str(), "it's");
+
+        // マッチに関与していないグループへの参照は失敗する
+        let re = Regex::new(r"(?:(a)|b)\1").unwrap();
+        assert!(!re.is_match("bb").unwrap());
+        assert!(re.is_match("aa").unwrap());
+
+        for engine in [Engine::BreadthFirst, Engine::Bounded] {
+            let res = RegexBuilder::new(r"(a)\1").engine(engine).build();
+            assert!(res.is_err(), "{engine:?}");
+        }
+        assert!(Regex::new(r"(a)\2").is_err());
+
+        // 命令を生成しないグループもキャプチャの数に含め、参照は失敗する
+        let re = Regex::new(r"(a){0}\1|(?<n>b)").unwrap();
+        assert_eq!(re.captures_len(), 3);
+        assert_eq!(re.capture_names().collect::<Vec<_>>(), vec![None, None, Some("n")]);
+        assert!(!re.is_match("a").unwrap());
+        let caps = re.captures("ab").unwrap().unwrap();
+        assert_eq!(caps.len(), 3);
+        assert!(caps.get(1).is_none());
+        assert_eq!(caps.name("n").unwrap().range(), 1..2);
+
+        let re = Regex::new(r"(?i)(a)\1(?-i)(k)\2").unwrap();
+        assert!(re.is_match("aAkk").unwrap());
+        assert!(!re.is_match("AakK").unwrap());
+        assert!(Regex::new(r"(?i)(k)\1").unwrap().is_match("k\u{212a}").unwrap());
+
+        // 参照先のグループの中にある後方参照はパース時にエラーとなる
+        let err = Regex::new(r"(a\1?)+").unwrap_err();
+        assert!(err.to_string().contains("backreference inside the group"), "{err}");
+        assert!(Regex::new(r"((a)\2)+\1").unwrap().is_match("aaaa").unwrap());
+    }
+
+    #[test]
+    fn test_look_around() {
+        let find = |expr: &str, line: &str| {
+            let re = Regex::new(expr).unwrap();
+            re.find(line).unwrap().map(|m| m.range())
+        };
+        assert_eq!(find("foo(?=bar)", "foobaz foobar"), Some(7..10));
+        assert_eq!(find("foo(?!bar)", "foobar foobaz"), Some(7..10));
+        assert_eq!(find(r#"(?<!\\)""#, r#"a\"b"c"#), Some(4..5));
+        assert_eq!(find(r"(?<=\$|USD )\d+", "12 USD 34"), Some(7..9));
+        assert_eq!(find(r"(?<=^|,)[^,]*(?=,|$)", "ab,cd"), Some(0..2));
+        assert_eq!(find(r"\b\w+(?<!ing)\b", "going gone"), Some(6..10));
+
+        // 肯定の先読みの中のキャプチャは残る
+        let re = Regex::new(r"(?=(\w+))\w").unwrap();
+        let caps = re.captures("ab").unwrap().unwrap();
+        assert_eq!(caps.get(1).unwrap().as_str(), "ab");
+
+        assert!(Regex::new("(?<=a+)b").is_err());
+        for engine in [Engine::BreadthFirst, Engine::Bounded] {
+            assert!(RegexBuilder::new("a(?=b)").engine(engine).build().is_err());
+        }
+    }
+
+    #[test]
+    fn test_atomic() {
+        let is_match = |expr: &str, line: &str| Regex::new(expr).unwrap().is_match(line).unwrap();
+        assert!(is_match("a(?>bc|b)c", "abcc"));
+        assert!(!is_match("a(?>bc|b)c", "abc"));
+        assert!(!is_match("^a*+a", "aaa"));
+        assert!(is_match("^a*a", "aaa"));
+        assert!(!is_match(r#""[^"]++""#, r#""abc"#));
+        assert!(is_match("^(?:ab?+)+c$", "abaabc"));
+
+        let re = Regex::new(r"(\d++)(\w)").unwrap();
+        let caps = re.captures("123a").unwrap().unwrap();
+        assert_eq!(caps.get(1).unwrap().as_str(), "123");
+        assert!(!re.is_match("123").unwrap());
+
+        // 強欲な繰り返しでは失敗した位置の組合せを試し直さないため、指数時間にならない
+        let re = Regex::new("^(?>a+)+b").unwrap();
+        assert!(!re.is_match(&"a".repeat(40)).unwrap());
+
+        for engine in [Engine::BreadthFirst, Engine::Bounded] {
+            assert!(RegexBuilder::new("a*+").engine(engine).build().is_err());
+        }
+    }
+
+    #[test]
+    fn test_unicode_property() {
+        let is_match = |expr: &str, line: &str| Regex::new(expr).unwrap().is_match(line).unwrap();
+        assert!(is_match(r"^\p{L}+$", "héllo世界"));
+        assert!(!is_match(r"^\p{L}+$", "abc1"));
+        assert!(is_match(r"^\p{Greek}+$", "αβγ"));
+        assert!(is_match(r"^\p{Han}{2}$", "漢字"));
+        assert!(is_match(r"^\P{Han}+$", "かな"));
+        assert!(is_match(r"^[\p{Lu}\d]+$", "AÉ9"));
+        assert!(!is_match(r"^[^\p{Lu}\d]+$", "aÉ"));
+        assert!(is_match(r"^\p{gc=Nd}\p{White_Space}\p{Alpha}$", "٣\u{3000}ж"));
+        assert!(is_match(r"(?i)^\p{Lu}+$", "abc"));
+        assert!(Regex::new(r"\p{Elvish}").is_err());
+    }
+
+    #[test]
+    fn test_posix() {
+        let is_match = |expr: &str, syntax: Syntax, line: &str| {
+            let re = RegexBuilder::new(expr).syntax(syntax).build().unwrap();
+            re.is_match(line).unwrap()
+        };
+        assert!(is_match(r"^[[:alpha:][:digit:]_]+$", Syntax::Standard, "abc_123"));
+        assert!(!is_match(r"^[[:alpha:]]+$", Syntax::Standard, "abc1"));
+        assert!(is_match(r"^[^[:space:]]+$", Syntax::Standard, "a-b"));
+        assert!(is_match(r"^[[:^digit:]]+$", Syntax::Standard, "xyz"));
+        assert!(!is_match(r"(?i)^[[:^lower:]]$", Syntax::Standard, "q"));
+        assert!(!is_match(r"(?i)^[[:^lower:]]$", Syntax::Standard, "Q"));
+        assert!(is_match(r"(?i)^[[:^lower:]]$", Syntax::Standard, "1"));
+
+        // EREでは`?`と`+`は接尾辞にならない
+        assert!(is_match(r"^(a+)?$", Syntax::PosixExtended, ""));
+        assert!(is_match(r"^a+?$", Syntax::PosixExtended, "aaa"));
+        assert!(RegexBuilder::new("(?i)a").syntax(Syntax::PosixExtended).build().is_err());
+
+        // BREでは`\(`や`\{`が演算子となり、`+`や`|`は通常の文字となる
+        assert!(is_match(r"^\(ab\)\{2\}$", Syntax::PosixBasic, "abab"));
+        assert!(is_match(r"^a+|b$", Syntax::PosixBasic, "a+|b"));
+        assert!(!is_match(r"^a+|b$", Syntax::PosixBasic, "a"));
+        assert!(is_match(r"^a\+\|b$", Syntax::PosixBasic, "aa"));
+        assert!(is_match(r"\(a\)b\1", Syntax::PosixBasic, "aba"));
+        assert!(is_match(r"*a", Syntax::PosixBasic, "*a"));
+        assert!(is_match(r"x^y$z", Syntax::PosixBasic, "x^y$z"));
+        assert!(is_match(r"^[(+]*$", Syntax::PosixBasic, "(+("));
+
+        // 括弧式の中の`\`は通常の文字
+        for syntax in [Syntax::PosixBasic, Syntax::PosixExtended] {
+            assert!(is_match(r"a[\]b", syntax, "a\\b"));
+            assert!(is_match(r"^[\n]$", syntax, "n"));
+            assert!(is_match(r"^[\n]$", syntax, "\\"));
+            assert!(!is_match(r"^[\n]$", syntax, "\n"));
+        }
+    }
+
+    #[test]
+    fn test_class_set_operation() {
+        let is_match = |expr: &str, line: &str| Regex::new(expr).unwrap().is_match(line).unwrap();
+        assert!(is_match(r"^[\p{L}--[aeiou]]+$", "xyzñ"));
+        assert!(!is_match(r"^[\p{L}--[aeiou]]+$", "xyza"));
+        assert!(is_match(r"^[\p{Greek}&&\p{Lu}]+$", "ΑΒΓ"));
+        assert!(!is_match(r"^[\p{Greek}&&\p{Lu}]+$", "Αβ"));
+        assert!(is_match(r"^[\w&&[^\d]]+$", "a_b"));
+        assert!(!is_match(r"^[\w&&[^\d]]+$", "a1"));
+        assert!(is_match(r"^[^[a-z]--[x]]$", "x"));
+        assert!(is_match(r"(?i)^[a-z--[aeiou]]+$", "XYZ"));
+        assert!(!is_match(r"(?i)^[a-z--[aeiou]]+$", "A"));
+        for c in ["k", "K", "\u{212a}"] {
+            assert!(is_match(r"(?i)^[^[^k]]$", c));
+        }
+        assert!(!is_match(r"(?i)^[^[^k]]$", "j"));
+    }
+
+    #[test]
+    fn test_quote() {
+        let re = Regex::new(r"(?i)^\Qa.b*\E+$").unwrap();
+        assert!(re.is_match("A.B*").unwrap());
+        assert!(re.is_match("a.b****").unwrap());
+        assert!(!re.is_match("axb").unwrap());
+        let re = Regex::new(r"(?x) \Q a # b \E").unwrap();
+        assert!(re.is_match(" a # b ").unwrap());
+
+        let re = Regex::new(&format!("^{}$", crate::escape("(1+2)*3 = [9]?"))).unwrap();
+        assert!(re.is_match("(1+2)*3 = [9]?").unwrap());
+        assert!(!re.is_match("1+2*3 = 9").unwrap());
+
+        let re = RegexBuilder::new(&format!("^{}$", crate::escape("a b#c\td")))
+            .ignore_whitespace(true)
+            .build()
+            .unwrap();
+        assert!(re.is_match("a b#c\td").unwrap());
+        assert!(!re.is_match("abcd").unwrap());
+
+        let re = Regex::new(&crate::escape("")).unwrap();
+        assert_eq!(re.find("abc").unwrap().map(|m| m.range()), Some(0..0));
+    }
+
+    #[test]
+    fn test_empty_loop() {
+        let tests = [
+            ("(a*)*", "b", 0..0, Some(0..0)),
+            ("(a*)*", "aab", 0..2, Some(0..2)),
+            ("(a?)*", "aab", 0..2, Some(1..2)),
+            ("(a*)+", "b", 0..0, Some(0..0)),
+            ("(a*)+b", "aab", 0..3, Some(0..2)),
+            ("(a*)+b", "b", 0..1, Some(0..0)),
+            ("(a*|b)*", "abab", 0..4, Some(3..4)),
+            ("((a*)*b)*c", "abbc", 0..4, Some(2..3)),
+            ("((a?)+)*", "aa", 0..2, Some(0..2)),
+            ("((?:(a)?|((b)*)))+", "ba", 0..0, Some(0..0)),
+            ("((b)*?)*", "bbb", 0..0, Some(0..0)),
+            ("((b)??)+", "baaa", 0..0, Some(0..0)),
+            ("((?:(?:\n|.)??)*)", "bb_aaa\n", 0..0, Some(0..0)),
+            ("((b){0,2}){1,}", "b", 0..1, Some(0..1)),
+        ];
+        for engine in [Engine::DepthFirst, Engine::BreadthFirst, Engine::Bounded] {
+            for (expr, line, all, group) in tests.iter().cloned() {
+                let re = RegexBuilder::new(expr).engine(engine).build().unwrap();
+                let caps = re.captures(line).unwrap().unwrap();
+                assert_eq!(caps.get(0).unwrap().range(), all, "{expr} {engine:?}");
+                assert_eq!(caps.get(1).map(|m| m.range()), group, "{expr} {engine:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_engines_agree() {
+        // 再現可能な疑似乱数（xorshift）
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+        let mut rand = move |n: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % n
+        };
+
+        // 空文字列にマッチし得る繰り返しを多く含むパターンを生成
+        fn gen(rand: &mut impl FnMut(u64) -> u64, depth: u32) -> String {
+            let quantifiers = ["*", "+", "?", "*?", "+?", "??", "{0,2}", "{1,}", "{2}", "{1,2}?"];
+            match rand(if depth == 0 { 4 } else { 12 }) {
+                0 => "a".to_string(),
+                1 => "b".to_string(),
+                2 => String::new(),
+                3 => ["^", "$", r"\b", "."][rand(4) as usize].to_string(),
+                4 | 5 => gen(rand, depth - 1) + &gen(rand, depth - 1),
+                6 => format!("{}|{}", gen(rand, depth - 1), gen(rand, depth - 1)),
+                7 => format!("({})", gen(rand, depth - 1)),
+                8 => format!("(?:{})", gen(rand, depth - 1)),
+                _ => {
+                    let q = quantifiers[rand(quantifiers.len() as u64) as usize];
+                    format!("({}){q}", gen(rand, depth - 1))
+                }
+            }
+        }
+
+        for _ in 0..3000 {
+            let expr = gen(&mut rand, 4);
+            let line = (0..rand(6))
+                .map(|_| if rand(2) == 0 { 'a' } else { 'b' })
+                .collect::<String>();
+            let Ok(re) = Regex::new(&expr) else {
+                continue;
+            };
+            let captures = |engine| {
+                let re = RegexBuilder::new(&expr).engine(engine).build().unwrap();
+                let caps = re.captures(&line).unwrap();
+                caps.map(|c| c.iter().map(|m| m.map(|m| m.range())).collect::<Vec<_>>())
+            };
+            let expected = captures(Engine::DepthFirst);
+            assert_eq!(captures(Engine::BreadthFirst), expected, "{expr} {line}");
+            assert_eq!(captures(Engine::Bounded), expected, "{expr} {line}");
+            let found = re.find(&line).unwrap().map(|m| m.range());
+            assert_eq!(found, expected.and_then(|c| c[0].clone()), "{expr} {line}");
+        }
+    }
+
+    #[test]
+    fn test_regex() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Regex>();
+
+        assert!(Regex::new("+b").is_err());
+
+        let re = Regex::new("abc|def").unwrap();
+        assert_eq!(re.as_str(), "abc|def");
+        assert!(re.is_match("abc").unwrap());
+        assert!(re.is_match("xxdefxx").unwrap());
+        assert!(!re.is_match("abdexf").unwrap());
+        assert!(!re.is_match("").unwrap());
+    }
+
+    #[test]
+    fn test_find() {
+        let re = Regex::new("ab+").unwrap();
+        let m = re.find("xxabbbyab").unwrap().unwrap();
+        assert_eq!((m.start(), m.end(), m.as_str()), (2, 6, "abbb"));
+        assert!(re.find("xyz").unwrap().is_none());
+
+        // バイト単位の位置
+        let m = re.find("あいab").unwrap().unwrap();
+        assert_eq!(m.range(), 6..8);
+
+        let found = re
+            .find_iter("ab abb xab")
+            .map(|m| m.unwrap().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(found, vec!["ab", "abb", "ab"]);
+
+        let re = Regex::new("a*").unwrap();
+        let found = re
+            .find_iter("baaあa")
+            .map(|m| m.unwrap().range())
+            .collect::<Vec<_>>();
+        assert_eq!(found, vec![0..0, 1..3, 6..7]);
+    }
+
+    #[test]
+    fn test_captures() {
+        let re = Regex::new("(a+)(b|(c))d").unwrap();
+        assert_eq!(re.captures_len(), 4);
+
+        let caps = re.captures("xaabdy").unwrap().unwrap();
+        assert_eq!(caps.len(), 4);
+        assert_eq!(caps.get(0).unwrap().as_str(), "aabd");
+        assert_eq!(caps.get(1).unwrap().range(), 1..3);
+        assert_eq!(caps.get(2).unwrap().as_str(), "b");
+        assert!(caps.get(3).is_none());
+        assert!(caps.get(4).is_none());
+        assert!(caps.get(usize::MAX).is_none());
+
+        let caps = re.captures("acd").unwrap().unwrap();
+        let groups = caps
+            .iter()
+            .map(|m| m.map(|m| m.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(groups, vec![Some("acd"), Some("a"), Some("c"), Some("c")]);
+
+        // 失敗した分岐で保存された位置は残らない
+        let re = Regex::new("(a)b|ac").unwrap();
+        let caps = re.captures("ac").unwrap().unwrap();
+        assert!(caps.get(1).is_none());
+
+        // 繰り返されたグループは最後の反復の位置
+        let re = Regex::new("(ab|cd)+").unwrap();
+        let caps = re.captures("abcdcd").unwrap().unwrap();
+        assert_eq!(caps.get(1).unwrap().range(), 4..6);
+
+        assert!(re.captures("xyz").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_max_stack_size() {
+        let line = "a".repeat(1 << 20);
+        let re = Regex::new("a*").unwrap();
+        assert_eq!(re.find(&line).unwrap().unwrap().end(), 1 << 20);
+
+        let re = RegexBuilder::new("a*")
+            .engine(Engine::DepthFirst)
+            .max_stack_size(16)
+            .build()
+            .unwrap();
+        assert!(re.find(&line).is_err());
+        assert!(re.is_match("aaa").unwrap());
     }
 }
\ No newline at end of file
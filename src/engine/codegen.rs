@@ -1,16 +1,27 @@
-use super::{parser::AST, Instruction};
+use super::{parser::{Look, AST}, Instruction};
 use crate::helper::safe_add;
 use std::{
     error::Error,
-    fmt::{self, Display, Formatter}
+    fmt::{self, Display, Formatter},
+    sync::Arc
 };
 
+/// 生成する命令数の既定の上限
+///
+/// 1命令の大きさは文字クラスの範囲の数によらないため、命令列のメモリ使用量もおおむね制限される。
+pub const DEFAULT_SIZE_LIMIT: usize = 1 << 20;
+
 #[derive(Debug)]
 pub enum CodeGenError {
     PCOverFlow,
+    SizeLimitExceeded,
     FailStar,
     FailOr,
     FailQuestion,
+    FailRepeat,
+    FailLookAround,
+    FailAtomic,
+    UnboundedLookBehind,
 }
 
 impl Display for CodeGenError {
@@ -25,11 +36,91 @@ impl Error for CodeGenError {}
 struct Generator {
     pc: usize,
     insts: Vec<Instruction>,
+    size_limit: usize, // 命令数の上限
+}
+
+/// 最大のキャプチャグループ番号
+pub fn max_capture(ast: &AST) -> usize {
+    match ast {
+        AST::Char(_) | AST::Class(..) | AST::Any(_) | AST::Assert(_) | AST::Backref(..) => 0,
+        AST::Star(e, _) | AST::Question(e, _) | AST::Plus(e, _) | AST::Atomic(e) => max_capture(e),
+        AST::Repeat { expr, .. } | AST::LookAround { expr, .. } => max_capture(expr),
+        AST::Capture(n, _, e) => max_capture(e).max(*n),
+        AST::Or(e1, e2) => max_capture(e1).max(max_capture(e2)),
+        AST::Seq(v) => v.iter().map(max_capture).max().unwrap_or(0),
+    }
+}
+
+/// キャプチャグループの名前を`names`のグループ番号の位置に設定
+fn collect_names(ast: &AST, names: &mut [Option<String>]) {
+    match ast {
+        AST::Char(_) | AST::Class(..) | AST::Any(_) | AST::Assert(_) | AST::Backref(..) => (),
+        AST::Star(e, _) | AST::Question(e, _) | AST::Plus(e, _) | AST::Atomic(e) => {
+            collect_names(e, names)
+        }
+        AST::Repeat { expr, .. } | AST::LookAround { expr, .. } => collect_names(expr, names),
+        AST::Capture(n, name, e) => {
+            names[*n] = name.clone();
+            collect_names(e, names);
+        }
+        AST::Or(e1, e2) => {
+            collect_names(e1, names);
+            collect_names(e2, names);
+        }
+        AST::Seq(v) => v.iter().for_each(|e| collect_names(e, names)),
+    }
+}
+
+/// マッチする文字列の最短と最長の長さ。最長が`None`の場合は上限なし
+fn width(ast: &AST) -> (usize, Option<usize>) {
+    match ast {
+        AST::Char(_) | AST::Class(..) | AST::Any(_) => (1, Some(1)),
+        AST::Assert(_) | AST::LookAround { .. } => (0, Some(0)),
+        AST::Backref(..) => (0, None),
+        AST::Star(e, _) => (0, width(e).1.filter(|m| *m == 0)),
+        AST::Plus(e, _) => {
+            let (min, max) = width(e);
+            (min, max.filter(|m| *m == 0))
+        }
+        AST::Question(e, _) => (0, width(e).1),
+        AST::Repeat { expr, min, max, .. } => {
+            let (emin, emax) = width(expr);
+            let max = match (emax, max) {
+                (Some(0), _) | (_, Some(0)) => Some(0),
+                (Some(emax), Some(max)) => emax.checked_mul(*max),
+                _ => None,
+            };
+            (emin.saturating_mul(*min), max)
+        }
+        AST::Capture(_, _, e) | AST::Atomic(e) => width(e),
+        AST::Or(e1, e2) => {
+            let ((min1, max1), (min2, max2)) = (width(e1), width(e2));
+            (min1.min(min2), max1.zip(max2).map(|(m1, m2)| m1.max(m2)))
+        }
+        AST::Seq(v) => v.iter().map(width).fold((0, Some(0)), |(min, max), (emin, emax)| {
+            let max = max.zip(emax).and_then(|(m, e)| m.checked_add(e));
+            (min.saturating_add(emin), max)
+        }),
+    }
+}
+
+/// 各キャプチャグループの名前を番号順に返す
+///
+/// グループ0はマッチ全体を表し、名前を持たない。
+pub fn capture_names(ast: &AST) -> Vec<Option<String>> {
+    let mut names = vec![None; max_capture(ast) + 1];
+    collect_names(ast, &mut names);
+    names
 }
 
 impl Generator {
     fn inc_pc(&mut self) -> Result<(), CodeGenError> {
-        safe_add(&mut self.pc, &1, || CodeGenError::PCOverFlow)
+        safe_add(&mut self.pc, &1, || CodeGenError::PCOverFlow)?;
+        if self.pc > self.size_limit {
+            Err(CodeGenError::SizeLimitExceeded)
+        } else {
+            Ok(())
+        }
     }
 
     fn gen_expr(&mut self, ast: &AST) -> Result<(), CodeGenError> {
@@ -37,30 +128,91 @@ impl Generator {
             AST::Char(c) => self.gen_char(*c)?,
             AST::Seq(v) => self.gen_seq(v)?,
             AST::Or(e1, e2) => self.gen_or(e1, e2)?,
-            AST::Plus(e) => self.gen_plus(e)?,
-            AST::Star(e) => self.gen_star(e)?,
-            AST::Question(e) => self.gen_question(e)?,
+            AST::Plus(e, greedy) => self.gen_plus(e, *greedy)?,
+            AST::Star(e, greedy) => self.gen_star(e, *greedy)?,
+            AST::Question(e, greedy) => self.gen_question(e, *greedy)?,
+            AST::Capture(n, _, e) => self.gen_capture(*n, e)?,
+            AST::Class(ranges, negated) => self.gen_class(ranges, *negated)?,
+            AST::Any(dot_all) => self.gen_any(*dot_all)?,
+            AST::Assert(look) => self.gen_assert(*look)?,
+            AST::Backref(n, case_insensitive) => self.gen_backref(*n, *case_insensitive)?,
+            AST::LookAround {
+                expr,
+                behind,
+                negated,
+            } => self.gen_look_around(expr, *behind, *negated)?,
+            AST::Atomic(e) => self.gen_atomic(e)?,
+            AST::Repeat {
+                expr,
+                min,
+                max,
+                greedy,
+            } => self.gen_repeat(expr, *min, *max, *greedy)?,
         }
         Ok(())
     }
 
-    fn gen_question(&mut self, e: &AST) -> Result<(), CodeGenError> {
-        let split_addr = self.pc;
+    /// キャプチャグループの前後で位置を保存する命令を生成
+    ///
+    /// グループnの開始位置はスロット2n、終了位置はスロット2n+1に保存する。
+    fn gen_capture(&mut self, n: usize, e: &AST) -> Result<(), CodeGenError> {
         self.inc_pc()?;
-        let split = Instruction::Split(self.pc, 0);
-        self.insts.push(split);
+        self.insts.push(Instruction::Save(n * 2));
 
         self.gen_expr(e)?;
 
-        if let Some(Instruction::Split(_, l2)) = self.insts.get_mut(split_addr) {
-            *l2 = self.pc;
+        self.inc_pc()?;
+        self.insts.push(Instruction::Save(n * 2 + 1));
+        Ok(())
+    }
+
+    /// `addr`にある`Split`の2つ目の分岐先を`exit`に設定
+    ///
+    /// 貪欲でない場合は分岐の優先度を入れ替え、`exit`を先に試す。
+    fn patch_split(
+        &mut self,
+        addr: usize,
+        exit: usize,
+        greedy: bool,
+        err: CodeGenError,
+    ) -> Result<(), CodeGenError> {
+        if let Some(Instruction::Split(l1, l2)) = self.insts.get_mut(addr) {
+            *l2 = exit;
+            if !greedy {
+                std::mem::swap(l1, l2);
+            }
             Ok(())
         } else {
-            Err(CodeGenError::FailQuestion)
+            Err(err)
         }
     }
 
-    fn gen_star(&mut self, e: &AST) -> Result<(), CodeGenError> {
+    fn gen_question(&mut self, e: &AST, greedy: bool) -> Result<(), CodeGenError> {
+        let split_addr = self.pc;
+        self.inc_pc()?;
+        let split = Instruction::Split(self.pc, 0);
+        self.insts.push(split);
+
+        self.gen_expr(e)?;
+
+        self.patch_split(split_addr, self.pc, greedy, CodeGenError::FailQuestion)
+    }
+
+    /// 本体が空文字列にマッチしうる場合は`(e+)?`として生成する
+    ///
+    /// `Jump`で先頭の`Split`に戻る形では、空文字列にマッチした反復が
+    /// 評価器に`(pc, sp)`の重複として打ち切られ、ループを抜ける経路ごと失われるため。
+    fn gen_star(&mut self, e: &AST, greedy: bool) -> Result<(), CodeGenError> {
+        if width(e).0 == 0 {
+            let split_addr = self.pc;
+            self.inc_pc()?;
+            self.insts.push(Instruction::Split(self.pc, 0));
+
+            self.gen_plus(e, greedy)?;
+
+            return self.patch_split(split_addr, self.pc, greedy, CodeGenError::FailStar);
+        }
+
         let l1 = self.pc;
         self.inc_pc()?;
         let split = Instruction::Split(self.pc, 0);
@@ -71,25 +223,67 @@ impl Generator {
         self.inc_pc()?;
         self.insts.push(Instruction::Jump(l1));
 
-        if let Some(Instruction::Split(_, l3)) = self.insts.get_mut(l1) {
-            *l3 = self.pc;
-            Ok(())
-        } else {
-            Err(CodeGenError::FailStar)
-        }
+        self.patch_split(l1, self.pc, greedy, CodeGenError::FailStar)
     }
 
-    fn gen_plus(&mut self, e: &AST) -> Result<(), CodeGenError> {
+    /// 空文字列にマッチした反復の後は、同じ位置で本体の先頭に戻る経路を評価器が打ち切り、
+    /// 2つ目の分岐からループを抜ける
+    fn gen_plus(&mut self, e: &AST, greedy: bool) -> Result<(), CodeGenError> {
         let l1 = self.pc;
         self.gen_expr(e)?;
 
         self.inc_pc()?;
-        let split = Instruction::Split(l1, self.pc);
+        let split = if greedy {
+            Instruction::Split(l1, self.pc)
+        } else {
+            Instruction::Split(self.pc, l1)
+        };
         self.insts.push(split);
 
         Ok(())
     }
 
+    /// 回数指定の繰り返しを展開
+    ///
+    /// `e{n,m}`は`e`をn回並べた後に`(e(e...)?)?`をm-n段入れ子にし、
+    /// `e{n,}`は`e`をn-1回並べた後に`e+`を続ける。`e{0,}`は`e*`と同じ。
+    fn gen_repeat(
+        &mut self,
+        e: &AST,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    ) -> Result<(), CodeGenError> {
+        let exact = if max.is_some() { min } else { min.saturating_sub(1) };
+        for _ in 0..exact {
+            let start = self.pc;
+            self.gen_expr(e)?;
+            if self.pc == start {
+                // 命令を生成しない式は何度繰り返しても同じ
+                return Ok(());
+            }
+        }
+
+        let max = match max {
+            Some(max) => max,
+            None if min == 0 => return self.gen_star(e, greedy),
+            None => return self.gen_plus(e, greedy),
+        };
+
+        let mut split_addrs = Vec::new();
+        for _ in min..max {
+            split_addrs.push(self.pc);
+            self.inc_pc()?;
+            self.insts.push(Instruction::Split(self.pc, 0));
+            self.gen_expr(e)?;
+        }
+
+        for split_addr in split_addrs {
+            self.patch_split(split_addr, self.pc, greedy, CodeGenError::FailRepeat)?;
+        }
+        Ok(())
+    }
+
     fn gen_seq(&mut self, exprs: &[AST]) -> Result<(), CodeGenError> {
         for e in exprs {
             self.gen_expr(e)?;
@@ -104,6 +298,95 @@ impl Generator {
         Ok(())
     }
 
+    /// 文字クラスの命令を生成
+    ///
+    /// 範囲はパース時に正規化済みで、繰り返しで展開した命令の間では複製せずに共有する。
+    fn gen_class(&mut self, ranges: &Arc<[(char, char)]>, negated: bool) -> Result<(), CodeGenError> {
+        let inst = Instruction::Class(Arc::clone(ranges), negated);
+        self.insts.push(inst);
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    fn gen_any(&mut self, dot_all: bool) -> Result<(), CodeGenError> {
+        self.insts.push(Instruction::Any(dot_all));
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    fn gen_assert(&mut self, look: Look) -> Result<(), CodeGenError> {
+        self.insts.push(Instruction::Assert(look));
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    fn gen_backref(&mut self, n: usize, case_insensitive: bool) -> Result<(), CodeGenError> {
+        self.insts.push(Instruction::Backref(n, case_insensitive));
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    /// 先読みと後読みのコードを生成
+    ///
+    /// ```text
+    ///     lookahead L1      ; 後読みの場合は lookbehind min..=max L1
+    ///     eのコード
+    ///     submatch
+    /// L1:
+    /// ```
+    ///
+    /// 後読みは本体の長さが有限でなければならない。
+    fn gen_look_around(&mut self, e: &AST, behind: bool, negated: bool) -> Result<(), CodeGenError> {
+        let look_addr = self.pc;
+        self.inc_pc()?;
+        if behind {
+            let (min, max) = width(e);
+            let max = max.ok_or(CodeGenError::UnboundedLookBehind)?;
+            self.insts.push(Instruction::LookBehind(negated, min..=max, 0));
+        } else {
+            self.insts.push(Instruction::LookAhead(negated, 0));
+        }
+
+        self.gen_expr(e)?;
+
+        self.inc_pc()?;
+        self.insts.push(Instruction::SubMatch);
+
+        match self.insts.get_mut(look_addr) {
+            Some(Instruction::LookAhead(_, next) | Instruction::LookBehind(_, _, next)) => {
+                *next = self.pc;
+                Ok(())
+            }
+            _ => Err(CodeGenError::FailLookAround),
+        }
+    }
+
+    /// アトミックグループのコードを生成
+    ///
+    /// ```text
+    ///     atomic L1
+    ///     eのコード
+    ///     submatch
+    /// L1:
+    /// ```
+    fn gen_atomic(&mut self, e: &AST) -> Result<(), CodeGenError> {
+        let atomic_addr = self.pc;
+        self.inc_pc()?;
+        self.insts.push(Instruction::Atomic(0));
+
+        self.gen_expr(e)?;
+
+        self.inc_pc()?;
+        self.insts.push(Instruction::SubMatch);
+
+        if let Some(Instruction::Atomic(next)) = self.insts.get_mut(atomic_addr) {
+            *next = self.pc;
+            Ok(())
+        } else {
+            Err(CodeGenError::FailAtomic)
+        }
+    }
+
     fn gen_or(&mut self, e1: &AST, e2: &AST) -> Result<(), CodeGenError> {
         let split_addr = self.pc;
         self.inc_pc()?;
@@ -142,19 +425,32 @@ impl Generator {
 }
 
 pub fn get_code(ast: &AST) -> Result<Vec<Instruction>, CodeGenError> {
-    let mut generator = Generator::default();
+    get_code_with_limit(ast, DEFAULT_SIZE_LIMIT)
+}
+
+/// 命令数の上限を指定してコード生成
+///
+/// 回数指定の繰り返しの展開などで命令数が`size_limit`を超えた場合はエラーを返す。
+/// 制限するのは命令数であり、評価時に入力の長さに応じて確保する領域は含まない。
+pub fn get_code_with_limit(ast: &AST, size_limit: usize) -> Result<Vec<Instruction>, CodeGenError> {
+    let mut generator = Generator {
+        size_limit,
+        ..Default::default()
+    };
     generator.gen_code(ast)?;
     Ok(generator.insts)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::engine::codegen::get_code;
-    use crate::engine::parser::AST;
+    use crate::engine::codegen::{get_code, get_code_with_limit, CodeGenError};
+    use crate::engine::parser::{parse, AST};
+    use crate::engine::Instruction;
+    use std::sync::Arc;
 
     #[test]
     fn test_get_code() {
-        let ast = AST::Seq(vec![AST::Plus(Box::new(AST::Char('a')))]);
+        let ast = AST::Seq(vec![AST::Plus(Box::new(AST::Char('a')), true)]);
         let code = get_code(&ast).unwrap();
         assert_eq!(
             code,
@@ -164,5 +460,82 @@ mod tests {
                 crate::engine::Instruction::Match,
             ]
         );
+
+        let code = get_code(&parse("(a*)*").unwrap()).unwrap();
+        assert_eq!(
+            code,
+            vec![
+                Instruction::Split(1, 7),
+                Instruction::Save(2),
+                Instruction::Split(3, 5),
+                Instruction::Char('a'),
+                Instruction::Jump(2),
+                Instruction::Save(3),
+                Instruction::Split(1, 7),
+                Instruction::Match,
+            ]
+        );
+
+        let code = get_code(&parse("ab{1,3}").unwrap()).unwrap();
+        assert_eq!(
+            code,
+            vec![
+                Instruction::Char('a'),
+                Instruction::Char('b'),
+                Instruction::Split(3, 6),
+                Instruction::Char('b'),
+                Instruction::Split(5, 6),
+                Instruction::Char('b'),
+                Instruction::Match,
+            ]
+        );
+
+        let code = get_code(&parse("a*?b??").unwrap()).unwrap();
+        assert_eq!(
+            code,
+            vec![
+                Instruction::Split(3, 1),
+                Instruction::Char('a'),
+                Instruction::Jump(0),
+                Instruction::Split(5, 4),
+                Instruction::Char('b'),
+                Instruction::Match,
+            ]
+        );
+
+        let code = get_code(&parse("(?<!ab?)c").unwrap()).unwrap();
+        assert_eq!(
+            code,
+            vec![
+                Instruction::LookBehind(true, 1..=2, 5),
+                Instruction::Char('a'),
+                Instruction::Split(3, 4),
+                Instruction::Char('b'),
+                Instruction::SubMatch,
+                Instruction::Char('c'),
+                Instruction::Match,
+            ]
+        );
+        assert!(matches!(
+            get_code(&parse("(?<=a(b|c+))").unwrap()),
+            Err(CodeGenError::UnboundedLookBehind)
+        ));
+        assert!(get_code(&parse("(?<=a(b|c{2}|(?=d+))x*{0})").unwrap()).is_ok());
+
+        let ast = parse("a{100000}{100000}").unwrap();
+        assert!(matches!(get_code(&ast), Err(CodeGenError::SizeLimitExceeded)));
+        let ast = parse("a{10}{10}").unwrap();
+        assert!(matches!(get_code_with_limit(&ast, 100), Err(CodeGenError::SizeLimitExceeded)));
+        assert_eq!(get_code_with_limit(&ast, 101).unwrap().len(), 101);
+
+        // 展開した文字クラスの命令は範囲を複製せずに共有する
+        let code = get_code(&parse(r"\p{L}{100000}").unwrap()).unwrap();
+        let Instruction::Class(ranges, false) = &code[0] else {
+            panic!("{:?}", code[0]);
+        };
+        assert_eq!(Arc::strong_count(ranges), 100000);
+        assert!(code[..100000]
+            .iter()
+            .all(|inst| matches!(inst, Instruction::Class(r, false) if Arc::ptr_eq(r, ranges))));
     }
 }
\ No newline at end of file
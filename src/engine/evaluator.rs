@@ -1,147 +1,264 @@
-use std::collections::VecDeque;
+mod backtrack;
+mod pike_vm;
+
 use std::error::Error;
 use std::fmt::{Display, Formatter};
-use crate::engine::Instruction;
-use crate::helper::safe_add;
+use crate::engine::{
+    parser::Look,
+    unicode_tables::{CASE_FOLDING_SIMPLE, PERL_WORD},
+    Instruction,
+};
+use backtrack::{eval_bounded, eval_depth};
+use pike_vm::eval_width;
+
+/// 深さ優先探索で使うスタック長の既定の上限
+pub const DEFAULT_MAX_STACK_SIZE: usize = 1 << 22;
+
+/// `Engine::Auto`が有界バックトラックを選ぶ訪問済み集合の大きさの上限（ビット数）
+const MAX_VISITED_BITS: usize = 256 * 1024 * 8;
 
 #[derive(Debug)]
 pub enum EvalError {
     PCOverFlow,
     SPOverFlow,
     InvalidPC,
-    InvalidContext,
+    InvalidSlot,
+    StackOverFlow,
+    UnsupportedEngine(Engine), // 評価器が対応していない命令を含む
 }
 
 impl Display for EvalError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "CodeGenError: {:?}", self)
+        match self {
+            EvalError::StackOverFlow => {
+                write!(f, "EvalError: backtrack stack exceeded the maximum size")
+            }
+            EvalError::InvalidSlot => write!(f, "EvalError: invalid capture slot"),
+            EvalError::UnsupportedEngine(engine) => {
+                write!(f, "EvalError: instruction not supported by engine: {engine:?}")
+            }
+            _ => write!(f, "CodeGenError: {:?}", self),
+        }
     }
 }
 
 impl Error for EvalError {}
 
-fn eval_depth(
-    inst: &[Instruction],
-    line: &[char],
-    mut pc: usize,
-    mut sp: usize,
-) -> Result<bool, EvalError> {
-    loop {
-        let next = if let Some(i) = inst.get(pc) {
-            i
-        } else {
-            return Err(EvalError::InvalidPC);
-        };
-
-        match next {
-            Instruction::Char(c) => {
-                if let Some(sp_c) = line.get(sp) {
-                    if c == sp_c {
-                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                        safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
-                    } else {
-                        return Ok(false);
-                    }
-                } else {
-                    return Ok(false)
-                }
-            }
-            Instruction::Match => {
-                return Ok(true);
-            }
-            Instruction::Jump(addr) => {
-                pc = *addr;
-            }
-            Instruction::Split(addr1, addr2) => {
-                if eval_depth(inst, line, *addr1, sp)? ||
-                    eval_depth(inst, line, *addr2, sp)? {
-                    return Ok(true);
-                } else {
-                    return Ok(false);
-                }
-            }
+/// マッチングに使う評価器
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Engine {
+    /// 入力と命令列が小さければ有界バックトラック、そうでなければ幅優先探索
+    ///
+    /// 後方参照、先読み、後読み、アトミックグループを含む場合は深さ優先探索
+    #[default]
+    Auto,
+    /// 深さ優先探索（バックトラック）。最悪計算量は指数時間
+    DepthFirst,
+    /// 幅優先探索（Pike VM）。計算量は O(命令数 × 入力長)
+    BreadthFirst,
+    /// 訪問済みの状態を記録する有界バックトラック。計算量は O(命令数 × 入力長) だが、
+    /// 同じ大きさのビット集合を確保する
+    Bounded,
+}
+
+/// 評価器の設定
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub engine: Engine,
+    pub max_stack_size: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            engine: Engine::Auto,
+            max_stack_size: DEFAULT_MAX_STACK_SIZE,
         }
     }
 }
 
-fn pop_ctx(
-    pc: &mut usize,
-    sp: &mut usize,
-    ctx: &mut VecDeque<(usize, usize)>,
-) -> Result<(), EvalError> {
-    if let Some((p, s)) = ctx.pop_back() {
-        *pc = p;
-        *sp = s;
-        Ok(())
+/// キャプチャ位置を保存し、保存前の値を返す
+fn save(slots: &mut [Option<usize>], n: usize, sp: usize) -> Result<Option<usize>, EvalError> {
+    if let Some(slot) = slots.get_mut(n) {
+        Ok(slot.replace(sp))
     } else {
-        Err(EvalError::InvalidContext)
+        Err(EvalError::InvalidSlot)
     }
 }
 
-fn eval_width(inst: &[Instruction], line: &[char]) -> Result<bool, EvalError> {
-    let mut ctx = VecDeque::new();
-    let mut pc = 0;
-    let mut sp = 0;
-
-    loop {
-        let next = if let Some(i) = inst.get(pc) {
-            i
-        } else {
-            return Err(EvalError::InvalidPC);
-        };
-
-        match next {
-            Instruction::Char(c) => {
-                if let Some(sp_c) = line.get(sp) {
-                    if c == sp_c {
-                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                        safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
-                    } else {
-                        if ctx.is_empty() {
-                            return Ok(false);
-                        } else {
-                            pop_ctx(&mut pc, &mut sp, &mut ctx)?;
-                        }
-                    }
-                } else {
-                    if ctx.is_empty() {
-                        return Ok(false);
-                    } else {
-                        pop_ctx(&mut pc, &mut sp, &mut ctx)?;
-                    }
-                }
-            }
-            Instruction::Match => {
-                return Ok(true);
-            }
-            Instruction::Jump(addr) => {
-                pc = *addr;
-            }
-            Instruction::Split(addr1, addr2) => {
-                pc = *addr1;
-                ctx.push_back((*addr2, sp));
-                continue;
+/// 命令列が使用するキャプチャ用スロットの数
+///
+/// スロット0と1はマッチ全体の開始位置と終了位置に使われる。
+pub fn num_slots(inst: &[Instruction]) -> usize {
+    inst.iter()
+        .filter_map(|i| match i {
+            Instruction::Save(n) => Some((n | 1) + 1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(2)
+}
+
+/// 1文字を読む命令が`c`にマッチするかを判定
+fn match_char(inst: &Instruction, c: char) -> bool {
+    match inst {
+        Instruction::Char(expected) => *expected == c,
+        Instruction::Class(ranges, negated) => in_ranges(ranges, c) != *negated,
+        Instruction::Any(dot_all) => *dot_all || c != '\n',
+        _ => false,
+    }
+}
+
+/// 昇順に並んだ重ならない範囲のいずれかに`c`が含まれるかを二分探索で判定
+fn in_ranges(ranges: &[(char, char)], c: char) -> bool {
+    ranges
+        .binary_search_by(|&(start, end)| {
+            if end < c {
+                std::cmp::Ordering::Less
+            } else if start > c {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Equal
             }
-        }
+        })
+        .is_ok()
+}
 
-        if !ctx.is_empty() {
-            ctx.push_back((pc, sp));
-            pop_ctx(&mut pc, &mut sp, &mut ctx)?;
-        }
+/// 単語を構成する文字かを判定
+///
+/// `unicode`が偽の場合はASCIIの英数字と`_`のみを対象とする。
+fn is_word_char(c: char, unicode: bool) -> bool {
+    if unicode {
+        in_ranges(PERL_WORD, c)
+    } else {
+        c.is_ascii_alphanumeric() || c == '_'
+    }
+}
+
+/// 位置`sp`の直前と直後の文字の一方のみが単語を構成する文字かを判定
+fn is_word_boundary(line: &[char], sp: usize, unicode: bool) -> bool {
+    let before = sp > 0 && line.get(sp - 1).is_some_and(|c| is_word_char(*c, unicode));
+    let after = line.get(sp).is_some_and(|c| is_word_char(*c, unicode));
+    before != after
+}
+
+/// グループ`n`がマッチした文字列が位置`sp`から続く場合、その長さを返す
+///
+/// グループがマッチに関与していない場合は失敗とする。`{0}`で命令が生成されなかったグループのように、
+/// スロットがない場合も関与していないものとみなす。
+/// `case_insensitive`が真の場合は、単純ケースフォールディングで同一視される文字も等しいとする。
+fn match_backref(
+    slots: &[Option<usize>],
+    n: usize,
+    case_insensitive: bool,
+    line: &[char],
+    sp: usize,
+) -> Option<usize> {
+    let captured = match (slots.get(n * 2), slots.get(n * 2 + 1)) {
+        (Some(Some(start)), Some(Some(end))) if start <= end => &line[*start..*end],
+        _ => return None,
+    };
+    let rest = line.get(sp..sp + captured.len())?;
+    let matched = if case_insensitive {
+        captured.iter().zip(rest).all(|(a, b)| is_same_case_fold(*a, *b))
+    } else {
+        captured == rest
+    };
+    matched.then_some(captured.len())
+}
+
+/// 2つの文字が単純ケースフォールディングで同一視されるかを判定
+fn is_same_case_fold(a: char, b: char) -> bool {
+    a == b
+        || CASE_FOLDING_SIMPLE
+            .binary_search_by_key(&a, |(c, _)| *c)
+            .is_ok_and(|i| CASE_FOLDING_SIMPLE[i].1.contains(&b))
+}
+
+/// 位置`sp`が表明を満たすかを判定
+fn is_look_satisfied(look: Look, line: &[char], sp: usize) -> bool {
+    match look {
+        Look::StartText => sp == 0,
+        Look::EndText => sp == line.len(),
+        Look::StartLine => sp == 0 || line.get(sp - 1) == Some(&'\n'),
+        Look::EndLine => sp == line.len() || line.get(sp) == Some(&'\n'),
+        Look::WordBoundary => is_word_boundary(line, sp, false),
+        Look::NotWordBoundary => !is_word_boundary(line, sp, false),
+        Look::WordBoundaryUnicode => is_word_boundary(line, sp, true),
+        Look::NotWordBoundaryUnicode => !is_word_boundary(line, sp, true),
+    }
+}
+
+/// 深さ優先探索でのみ評価できる命令を含むかを判定
+fn needs_backtrack(inst: &[Instruction]) -> bool {
+    inst.iter().any(|i| {
+        matches!(
+            i,
+            Instruction::Backref(..)
+                | Instruction::LookAhead(..)
+                | Instruction::LookBehind(..)
+                | Instruction::Atomic(_)
+        )
+    })
+}
+
+/// 命令列を評価できる評価器を選ぶ
+///
+/// 後方参照、先読み、後読み、アトミックグループを含む命令列の場合、`Engine::Auto`は深さ優先探索を選び、
+/// ほかの評価器は`EvalError::UnsupportedEngine`を返す。
+pub fn resolve_engine(inst: &[Instruction], engine: Engine) -> Result<Engine, EvalError> {
+    if !needs_backtrack(inst) {
+        return Ok(engine);
+    }
+    match engine {
+        Engine::Auto | Engine::DepthFirst => Ok(Engine::DepthFirst),
+        engine => Err(EvalError::UnsupportedEngine(engine)),
     }
 }
 
+/// 先頭位置で命令列を実行し、マッチするかを判定
 pub fn eval(inst: &[Instruction], line: &[char], is_depth: bool) -> Result<bool, EvalError> {
+    let mut slots = vec![None; num_slots(inst)];
     if is_depth {
-        eval_depth(inst, line, 0, 0)
+        eval_depth(inst, line, 0, true, DEFAULT_MAX_STACK_SIZE, &mut slots)
     } else {
-        eval_width(inst, line)
+        eval_width(inst, line, 0, true, &mut slots)
     }
 }
 
+/// `start`以降で最も左にあるマッチを探索し、キャプチャ位置を返す
+///
+/// 返り値の長さは`nslots`で、グループnの開始位置と終了位置がそれぞれ2n番目と2n+1番目に入る。
+/// 位置は`line`の文字単位のインデックス。
+pub fn search(
+    inst: &[Instruction],
+    line: &[char],
+    start: usize,
+    nslots: usize,
+    config: &Config,
+) -> Result<Option<Vec<Option<usize>>>, EvalError> {
+    let mut slots = vec![None; nslots];
+    let engine = match config.engine {
+        Engine::Auto if inst.len().saturating_mul(line.len() + 1) <= MAX_VISITED_BITS => {
+            Engine::Bounded
+        }
+        Engine::Auto => Engine::BreadthFirst,
+        engine => engine,
+    };
+    let matched = match engine {
+        Engine::DepthFirst => {
+            eval_depth(inst, line, start, false, config.max_stack_size, &mut slots)?
+        }
+        Engine::Bounded => eval_bounded(inst, line, start, false, &mut slots)?,
+        _ => eval_width(inst, line, start, false, &mut slots)?,
+    };
+    Ok(matched.then_some(slots))
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::engine::evaluator::eval;
+    use crate::engine::evaluator::{eval, search, Config, Engine, EvalError};
+    use crate::engine::Instruction;
 
     #[test]
     fn test_eval_depth() {
@@ -153,4 +270,38 @@ mod tests {
         let res = eval(&instruction, &"aaa".chars().collect::<Vec<char>>(), true).unwrap();
         assert_eq!(res, true);
     }
+
+    #[test]
+    fn test_search() {
+        // a(b+)
+        let instruction = vec![
+            Instruction::Char('a'),
+            Instruction::Save(2),
+            Instruction::Char('b'),
+            Instruction::Split(2, 4),
+            Instruction::Save(3),
+            Instruction::Match,
+        ];
+        let line = "xxabbxab".chars().collect::<Vec<char>>();
+        for engine in [Engine::Auto, Engine::DepthFirst, Engine::BreadthFirst, Engine::Bounded] {
+            let config = Config {
+                engine,
+                ..Default::default()
+            };
+            let res = search(&instruction, &line, 0, 4, &config).unwrap();
+            assert_eq!(res, Some(vec![Some(2), Some(5), Some(3), Some(5)]));
+            let res = search(&instruction, &line, 3, 4, &config).unwrap();
+            assert_eq!(res, Some(vec![Some(6), Some(8), Some(7), Some(8)]));
+            assert_eq!(search(&instruction, &line, 7, 4, &config).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_eval_error_display() {
+        assert_eq!(
+            EvalError::StackOverFlow.to_string(),
+            "EvalError: backtrack stack exceeded the maximum size"
+        );
+        assert_eq!(EvalError::InvalidSlot.to_string(), "EvalError: invalid capture slot");
+    }
 }
\ No newline at end of file
@@ -0,0 +1,485 @@
+//! 深さ優先探索による評価器（バックトラック）
+//!
+//! 再帰呼び出しの代わりにヒープ上のスタックに分岐とキャプチャの復元を積むため、
+//! 長い入力でもネイティブスタックを使い果たさない。
+//!
+//! 訪問済みの状態を記録する有界バックトラックでは、各状態を高々1回しか探索しないため、
+//! 計算量は O(命令数 × 入力長) に収まる。
+//!
+//! 空文字列にマッチした繰り返しの反復は、同じ位置で同じ命令に戻る閉路となる。
+//! 有界バックトラックでは訪問済みの状態として、深さ優先探索では現在の経路上の状態として打ち切るため、
+//! どちらもPike VMと同じく`(pc, sp)`の最初の到達のみを探索する。
+use super::{Engine, EvalError};
+use crate::engine::Instruction;
+use crate::helper::safe_add;
+
+/// バックトラック用スタックに積む作業
+enum Job {
+    /// 分岐先の命令と位置
+    Explore(usize, usize),
+    /// キャプチャ位置の復元
+    Restore(usize, Option<usize>),
+    /// 位置を開始位置としてマッチ全体の探索を始める。ほかのどの分岐よりも優先度が低い
+    Start(usize),
+    /// 命令を現在の経路から外し、経路上にあった位置を復元
+    Leave(usize, Option<usize>),
+}
+
+/// 訪問済みの`(pc, sp)`を記録するビット集合
+struct Visited {
+    bits: Vec<u64>,
+    stride: usize,
+}
+
+impl Visited {
+    fn new(inst_len: usize, line_len: usize) -> Visited {
+        let stride = line_len + 1;
+        Visited {
+            bits: vec![0; (inst_len * stride).div_ceil(64)],
+            stride,
+        }
+    }
+
+    /// 未訪問であれば訪問済みにして真を返す
+    fn insert(&mut self, pc: usize, sp: usize) -> bool {
+        let i = pc * self.stride + sp;
+        let (word, bit) = (i / 64, 1 << (i % 64));
+        let fresh = self.bits[word] & bit == 0;
+        self.bits[word] |= bit;
+        fresh
+    }
+}
+
+/// 深さ優先探索の実行状態
+struct Backtracker<'a> {
+    inst: &'a [Instruction],
+    line: &'a [char],
+    stack: Vec<Job>,
+    max_stack_size: usize,
+    visited: Option<Visited>,
+    cycles: Vec<bool>,           // ε遷移の閉路上にある命令
+    on_path: Vec<Option<usize>>, // 閉路上の命令が現在の経路にある場合、その位置
+}
+
+/// 入力を読まずに遷移できる次の命令
+fn epsilon_next(inst: &Instruction, pc: usize) -> [Option<usize>; 2] {
+    match inst {
+        Instruction::Jump(addr) => [Some(*addr), None],
+        Instruction::Split(addr1, addr2) => [Some(*addr1), Some(*addr2)],
+        Instruction::Save(_) | Instruction::Assert(_) | Instruction::Backref(..) => {
+            [Some(pc + 1), None]
+        }
+        Instruction::LookAhead(_, next)
+        | Instruction::LookBehind(_, _, next)
+        | Instruction::Atomic(next) => [Some(*next), None],
+        Instruction::Char(_)
+        | Instruction::Class(..)
+        | Instruction::Any(_)
+        | Instruction::Match
+        | Instruction::SubMatch => [None, None],
+    }
+}
+
+/// ε遷移の閉路上にある命令を強連結成分分解（Tarjanの方法）で求める
+///
+/// 空文字列にマッチし得る繰り返しの本体とその分岐が該当する。
+fn epsilon_cycles(inst: &[Instruction]) -> Vec<bool> {
+    let n = inst.len();
+    let mut index = vec![usize::MAX; n];
+    let mut low = vec![0; n];
+    let mut on_stack = vec![false; n];
+    let mut stack = Vec::new();
+    let mut cycles = vec![false; n];
+    let mut count = 0;
+
+    for root in 0..n {
+        if index[root] != usize::MAX {
+            continue;
+        }
+        // 再帰の代わりに、命令と次に調べる遷移先の番号を積む
+        let mut calls = vec![(root, 0)];
+        index[root] = count;
+        low[root] = count;
+        count += 1;
+        stack.push(root);
+        on_stack[root] = true;
+
+        while let Some(&(v, i)) = calls.last() {
+            if let Some(next) = epsilon_next(&inst[v], v).get(i) {
+                calls.last_mut().unwrap().1 += 1;
+                match next.filter(|w| *w < n) {
+                    Some(w) if w == v => cycles[v] = true,
+                    Some(w) if index[w] == usize::MAX => {
+                        index[w] = count;
+                        low[w] = count;
+                        count += 1;
+                        stack.push(w);
+                        on_stack[w] = true;
+                        calls.push((w, 0));
+                    }
+                    Some(w) if on_stack[w] => low[v] = low[v].min(index[w]),
+                    _ => (),
+                }
+                continue;
+            }
+
+            calls.pop();
+            if let Some(&(u, _)) = calls.last() {
+                low[u] = low[u].min(low[v]);
+            }
+            if low[v] == index[v] {
+                let mut component = Vec::new();
+                while let Some(w) = stack.pop() {
+                    on_stack[w] = false;
+                    component.push(w);
+                    if w == v {
+                        break;
+                    }
+                }
+                if component.len() > 1 {
+                    for w in component {
+                        cycles[w] = true;
+                    }
+                }
+            }
+        }
+    }
+    cycles
+}
+
+impl<'a> Backtracker<'a> {
+    /// スタック長を`max_stack_size`以下に保ちながら作業を積む
+    fn push(&mut self, job: Job) -> Result<(), EvalError> {
+        if self.stack.len() >= self.max_stack_size {
+            return Err(EvalError::StackOverFlow);
+        }
+        self.stack.push(job);
+        Ok(())
+    }
+
+    /// `job`から深さ優先で実行し、マッチした位置を返す
+    ///
+    /// `visited`がある場合、訪問済みの状態はすでに失敗したものとして探索を打ち切る。
+    /// 先読みと後読みの本体は`SubMatch`で終わり、`target`がある場合はその位置で終わるもののみ成功とする。
+    fn run(
+        &mut self,
+        slots: &mut [Option<usize>],
+        job: Job,
+        target: Option<usize>,
+    ) -> Result<Option<usize>, EvalError> {
+        self.stack.clear();
+        self.push(job)?;
+
+        while let Some(job) = self.stack.pop() {
+            let (mut pc, mut sp) = match job {
+                Job::Explore(pc, sp) => (pc, sp),
+                Job::Restore(n, old) => {
+                    slots[n] = old;
+                    continue;
+                }
+                Job::Leave(pc, old) => {
+                    self.on_path[pc] = old;
+                    continue;
+                }
+                Job::Start(sp) => {
+                    // 次の開始位置は、この位置から始まる探索がすべて失敗した後に試す
+                    if sp < self.line.len() {
+                        self.push(Job::Start(sp + 1))?;
+                    }
+                    slots[0] = Some(sp);
+                    (0, sp)
+                }
+            };
+
+            loop {
+                let next = if let Some(i) = self.inst.get(pc) {
+                    i
+                } else {
+                    return Err(EvalError::InvalidPC);
+                };
+
+                if let Some(visited) = &mut self.visited {
+                    if !visited.insert(pc, sp) {
+                        break;
+                    }
+                } else if self.cycles[pc] {
+                    // 同じ位置で経路上の命令に戻った場合は、空文字列にマッチした反復として打ち切る
+                    if self.on_path[pc] == Some(sp) {
+                        break;
+                    }
+                    let old = self.on_path[pc].replace(sp);
+                    self.push(Job::Leave(pc, old))?;
+                }
+
+                match next {
+                    Instruction::Char(_) | Instruction::Class(..) | Instruction::Any(_) => {
+                        if self.line.get(sp).is_some_and(|c| super::match_char(next, *c)) {
+                            safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                            safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
+                        } else {
+                            break;
+                        }
+                    }
+                    Instruction::Match => {
+                        return Ok(Some(sp));
+                    }
+                    Instruction::SubMatch => {
+                        if target.is_some_and(|t| t != sp) {
+                            break;
+                        }
+                        return Ok(Some(sp));
+                    }
+                    Instruction::Jump(addr) => {
+                        pc = *addr;
+                    }
+                    Instruction::Split(addr1, addr2) => {
+                        self.push(Job::Explore(*addr2, sp))?;
+                        pc = *addr1;
+                    }
+                    Instruction::Save(n) => {
+                        // バックトラック時に保存前の位置に戻す
+                        let old = super::save(slots, *n, sp)?;
+                        self.push(Job::Restore(*n, old))?;
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                    }
+                    Instruction::Assert(look) => {
+                        if !super::is_look_satisfied(*look, self.line, sp) {
+                            break;
+                        }
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                    }
+                    Instruction::Backref(n, case_insensitive) => {
+                        // 訪問済みの状態はキャプチャ位置によって結果が変わるため記録できない
+                        if self.visited.is_some() {
+                            return Err(EvalError::UnsupportedEngine(Engine::Bounded));
+                        }
+                        let len = super::match_backref(slots, *n, *case_insensitive, self.line, sp);
+                        if let Some(len) = len {
+                            safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                            safe_add(&mut sp, &len, || EvalError::SPOverFlow)?;
+                        } else {
+                            break;
+                        }
+                    }
+                    Instruction::LookAhead(negated, next) => {
+                        let matched = self.run_sub(slots, pc + 1, [sp], None, !negated)?;
+                        if matched.is_some() == *negated {
+                            break;
+                        }
+                        pc = *next;
+                    }
+                    Instruction::LookBehind(negated, len, next) => {
+                        // 入力の先頭より前から始まる長さは試さない
+                        let starts = (sp.saturating_sub(*len.end())..=sp)
+                            .filter(|start| len.contains(&(sp - start)));
+                        let matched = self.run_sub(slots, pc + 1, starts, Some(sp), !negated)?;
+                        if matched.is_some() == *negated {
+                            break;
+                        }
+                        pc = *next;
+                    }
+                    Instruction::Atomic(next) => {
+                        if let Some(end) = self.run_sub(slots, pc + 1, [sp], None, true)? {
+                            pc = *next;
+                            sp = end;
+                        } else {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// 先読み、後読み、アトミックグループの本体を`starts`の各位置から実行し、
+    /// 最初に見つかったマッチの終了位置を返す
+    ///
+    /// 本体の探索は現在の探索と独立しており、マッチした後は本体の中の分岐に戻らない。
+    /// `keep`が真でマッチした場合は本体の中で保存したキャプチャ位置を残し、
+    /// バックトラック時に復元する作業を積む。そうでなければキャプチャ位置を元に戻す。
+    fn run_sub(
+        &mut self,
+        slots: &mut [Option<usize>],
+        body: usize,
+        starts: impl IntoIterator<Item = usize>,
+        target: Option<usize>,
+        keep: bool,
+    ) -> Result<Option<usize>, EvalError> {
+        if self.visited.is_some() {
+            return Err(EvalError::UnsupportedEngine(Engine::Bounded));
+        }
+
+        let saved = slots.to_vec();
+        let outer = std::mem::take(&mut self.stack);
+        let mut matched = None;
+        for start in starts {
+            matched = self.run(slots, Job::Explore(body, start), target)?;
+            if matched.is_some() {
+                break;
+            }
+        }
+        // 本体の中で経路に加えた命令を外す
+        while let Some(job) = self.stack.pop() {
+            if let Job::Leave(pc, old) = job {
+                self.on_path[pc] = old;
+            }
+        }
+        self.stack = outer;
+
+        if matched.is_some() && keep {
+            for (n, old) in saved.into_iter().enumerate() {
+                if slots[n] != old {
+                    self.push(Job::Restore(n, old))?;
+                }
+            }
+        } else {
+            slots.copy_from_slice(&saved);
+        }
+        Ok(matched)
+    }
+
+    /// `start`以降の最も左にあるマッチを探索
+    ///
+    /// 開始位置をずらす処理は`Job::Start`としてスタックの底に積むため、
+    /// 位置ごとに探索をやり直すことなく1回の実行で最も左のマッチが見つかる。
+    fn search(
+        &mut self,
+        start: usize,
+        anchored: bool,
+        slots: &mut [Option<usize>],
+    ) -> Result<bool, EvalError> {
+        slots.fill(None);
+        let job = if anchored {
+            slots[0] = Some(start);
+            Job::Explore(0, start)
+        } else {
+            Job::Start(start)
+        };
+        if let Some(e) = self.run(slots, job, None)? {
+            slots[1] = Some(e);
+            Ok(true)
+        } else {
+            slots.fill(None);
+            Ok(false)
+        }
+    }
+}
+
+/// 深さ優先探索で`start`以降の最も左にあるマッチを探索
+///
+/// `anchored`が真の場合は`start`から始まるマッチのみを探索する。
+/// マッチした場合は`slots`にキャプチャ位置を書き込み、真を返す。
+/// スタック長が`max_stack_size`を超えた場合は`EvalError::StackOverFlow`を返す。
+pub fn eval_depth(
+    inst: &[Instruction],
+    line: &[char],
+    start: usize,
+    anchored: bool,
+    max_stack_size: usize,
+    slots: &mut [Option<usize>],
+) -> Result<bool, EvalError> {
+    let mut backtracker = Backtracker {
+        inst,
+        line,
+        stack: Vec::new(),
+        max_stack_size,
+        visited: None,
+        cycles: epsilon_cycles(inst),
+        on_path: vec![None; inst.len()],
+    };
+    backtracker.search(start, anchored, slots)
+}
+
+/// 訪問済みの状態を記録しながら深さ優先探索で`start`以降の最も左にあるマッチを探索
+///
+/// 失敗した状態は開始位置によらず失敗するため、訪問済みの集合は開始位置をまたいで共有する。
+/// スタックに積まれる作業は状態数で抑えられるため、スタック長の上限は設けない。
+pub fn eval_bounded(
+    inst: &[Instruction],
+    line: &[char],
+    start: usize,
+    anchored: bool,
+    slots: &mut [Option<usize>],
+) -> Result<bool, EvalError> {
+    let mut backtracker = Backtracker {
+        inst,
+        line,
+        stack: Vec::new(),
+        max_stack_size: usize::MAX,
+        visited: Some(Visited::new(inst.len(), line.len())),
+        cycles: Vec::new(),
+        on_path: Vec::new(),
+    };
+    backtracker.search(start, anchored, slots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{epsilon_cycles, eval_bounded, eval_depth};
+    use crate::engine::evaluator::EvalError;
+    use crate::engine::{codegen::get_code, parser::parse};
+
+    #[test]
+    fn test_eval_depth_stack() {
+        // 再帰による実装では1MBの入力でネイティブスタックがあふれていた
+        let code = get_code(&parse("(a*)b").unwrap()).unwrap();
+        let line = "a".repeat(1 << 20).chars().collect::<Vec<char>>();
+        let mut slots = vec![None; 4];
+        assert!(!eval_depth(&code, &line, 0, true, 1 << 22, &mut slots).unwrap());
+
+        let line = "aaaab".chars().collect::<Vec<char>>();
+        assert!(eval_depth(&code, &line, 0, true, 1 << 22, &mut slots).unwrap());
+        assert_eq!(slots, vec![Some(0), Some(5), Some(0), Some(4)]);
+
+        assert!(matches!(
+            eval_depth(&code, &line, 0, true, 4, &mut slots),
+            Err(EvalError::StackOverFlow)
+        ));
+
+        // 開始位置をずらす探索も1回の実行の中で行う
+        let line = "xxaab".chars().collect::<Vec<char>>();
+        assert!(eval_depth(&code, &line, 0, false, 1 << 22, &mut slots).unwrap());
+        assert_eq!(slots, vec![Some(2), Some(5), Some(2), Some(4)]);
+        let line = "xxaa".chars().collect::<Vec<char>>();
+        assert!(!eval_depth(&code, &line, 0, false, 1 << 22, &mut slots).unwrap());
+        assert_eq!(slots, vec![None; 4]);
+    }
+
+    #[test]
+    fn test_epsilon_cycles() {
+        // (a*)*の外側の繰り返しは、内側が空文字列にマッチすると入力を読まずに本体の先頭へ戻る
+        let code = get_code(&parse("(a*)*").unwrap()).unwrap();
+        assert_eq!(
+            epsilon_cycles(&code),
+            vec![false, true, true, false, false, true, true, false]
+        );
+        let code = get_code(&parse("(ab)*").unwrap()).unwrap();
+        assert!(epsilon_cycles(&code).iter().all(|c| !c));
+    }
+
+    #[test]
+    fn test_eval_bounded() {
+        let eval = |expr: &str, line: &str| {
+            let code = get_code(&parse(expr).unwrap()).unwrap();
+            let line = line.chars().collect::<Vec<char>>();
+            let mut slots = vec![None; 6];
+            eval_bounded(&code, &line, 0, false, &mut slots).unwrap().then_some(slots)
+        };
+
+        let res = eval("a(b|bc)d", "xabcd");
+        assert_eq!(res, Some(vec![Some(1), Some(5), Some(2), Some(4), None, None]));
+        let res = eval("(a|ab)(c|bcd)", "abcd");
+        assert_eq!(res, Some(vec![Some(0), Some(4), Some(0), Some(1), Some(1), Some(4)]));
+        assert_eq!(eval("ab", "aac"), None);
+
+        // 訪問済みの状態を再探索しないため指数時間にならない
+        let expr = format!("{}{}", "(a?)".repeat(30), "a".repeat(30));
+        let code = get_code(&parse(&expr).unwrap()).unwrap();
+        let line = "a".repeat(30).chars().collect::<Vec<char>>();
+        let mut slots = vec![None; 62];
+        assert!(eval_bounded(&code, &line, 0, true, &mut slots).unwrap());
+        assert_eq!(slots[1], Some(30));
+    }
+}
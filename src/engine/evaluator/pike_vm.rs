@@ -0,0 +1,217 @@
+//! 幅優先探索による評価器（Pike VM）
+//!
+//! 入力を1文字ずつ読み進めながら、実行中のスレッドをすべて同時に進める。
+//! スレッドはプログラムカウンタで重複を除くため、計算量は O(命令数 × 入力長) に収まる。
+use super::{Engine, EvalError};
+use crate::engine::Instruction;
+use crate::helper::safe_add;
+
+/// プログラムカウンタをキーとするスレッドの集合
+///
+/// `dense`は追加された順、すなわち優先度の高い順にプログラムカウンタを保持する。
+/// 各スレッドのキャプチャ位置は`caps`の`pc * nslots`番目から格納する。
+struct Threads {
+    dense: Vec<usize>,
+    sparse: Vec<usize>,
+    caps: Vec<Option<usize>>,
+    nslots: usize,
+}
+
+impl Threads {
+    fn new(len: usize, nslots: usize) -> Threads {
+        Threads {
+            dense: Vec::with_capacity(len),
+            sparse: vec![0; len],
+            caps: vec![None; len * nslots],
+            nslots,
+        }
+    }
+
+    fn contains(&self, pc: usize) -> bool {
+        let i = self.sparse[pc];
+        i < self.dense.len() && self.dense[i] == pc
+    }
+
+    fn insert(&mut self, pc: usize) {
+        self.sparse[pc] = self.dense.len();
+        self.dense.push(pc);
+    }
+
+    fn caps(&self, pc: usize) -> &[Option<usize>] {
+        &self.caps[pc * self.nslots..(pc + 1) * self.nslots]
+    }
+
+    fn caps_mut(&mut self, pc: usize) -> &mut [Option<usize>] {
+        &mut self.caps[pc * self.nslots..(pc + 1) * self.nslots]
+    }
+
+    fn clear(&mut self) {
+        self.dense.clear();
+    }
+}
+
+/// ε遷移をたどる際の作業
+enum Job {
+    Explore(usize),
+    Restore(usize, Option<usize>),
+}
+
+/// `pc`からε遷移でたどれるスレッドを`threads`に追加
+///
+/// `Split`は1つ目の分岐を先にたどるため、`threads`には優先度の高い順に追加される。
+fn add_thread(
+    inst: &[Instruction],
+    line: &[char],
+    threads: &mut Threads,
+    stack: &mut Vec<Job>,
+    caps: &mut [Option<usize>],
+    pc: usize,
+    sp: usize,
+) -> Result<(), EvalError> {
+    stack.push(Job::Explore(pc));
+    while let Some(job) = stack.pop() {
+        let mut pc = match job {
+            Job::Explore(pc) => pc,
+            Job::Restore(n, old) => {
+                caps[n] = old;
+                continue;
+            }
+        };
+
+        loop {
+            let next = if let Some(i) = inst.get(pc) {
+                i
+            } else {
+                return Err(EvalError::InvalidPC);
+            };
+
+            // 空文字列にマッチした反復のように、同じ位置で追加済みの命令に戻るスレッドは捨てる
+            if threads.contains(pc) {
+                break;
+            }
+            threads.insert(pc);
+
+            match next {
+                Instruction::Jump(addr) => pc = *addr,
+                Instruction::Split(addr1, addr2) => {
+                    stack.push(Job::Explore(*addr2));
+                    pc = *addr1;
+                }
+                Instruction::Save(n) => {
+                    let old = super::save(caps, *n, sp)?;
+                    stack.push(Job::Restore(*n, old));
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                }
+                Instruction::Assert(look) => {
+                    if !super::is_look_satisfied(*look, line, sp) {
+                        break;
+                    }
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                }
+                Instruction::Backref(..)
+                | Instruction::LookAhead(..)
+                | Instruction::LookBehind(..)
+                | Instruction::Atomic(_)
+                | Instruction::SubMatch => {
+                    return Err(EvalError::UnsupportedEngine(Engine::BreadthFirst));
+                }
+                Instruction::Char(_)
+                | Instruction::Class(..)
+                | Instruction::Any(_)
+                | Instruction::Match => {
+                    threads.caps_mut(pc).copy_from_slice(caps);
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 幅優先探索で`start`以降の最も左にあるマッチを探索
+///
+/// `anchored`が真の場合は`start`から始まるマッチのみを探索する。
+/// マッチした場合は`slots`にキャプチャ位置を書き込み、真を返す。
+pub fn eval_width(
+    inst: &[Instruction],
+    line: &[char],
+    start: usize,
+    anchored: bool,
+    slots: &mut [Option<usize>],
+) -> Result<bool, EvalError> {
+    let nslots = slots.len();
+    let mut clist = Threads::new(inst.len(), nslots);
+    let mut nlist = Threads::new(inst.len(), nslots);
+    let mut stack = Vec::new();
+    let mut caps = vec![None; nslots];
+    let mut matched = false;
+
+    for sp in start..=line.len() {
+        // マッチが見つかるまでは各位置から新しいスレッドを最低の優先度で開始する
+        if !matched && (!anchored || sp == start) {
+            caps.fill(None);
+            caps[0] = Some(sp);
+            add_thread(inst, line, &mut clist, &mut stack, &mut caps, 0, sp)?;
+        }
+
+        if clist.dense.is_empty() {
+            break;
+        }
+
+        for i in 0..clist.dense.len() {
+            let pc = clist.dense[i];
+            match &inst[pc] {
+                Instruction::Match => {
+                    // 優先度の低いスレッドは破棄する
+                    slots.copy_from_slice(clist.caps(pc));
+                    slots[1] = Some(sp);
+                    matched = true;
+                    break;
+                }
+                next if line.get(sp).is_some_and(|c| super::match_char(next, *c)) => {
+                    caps.copy_from_slice(clist.caps(pc));
+                    add_thread(inst, line, &mut nlist, &mut stack, &mut caps, pc + 1, sp + 1)?;
+                }
+                _ => (),
+            }
+        }
+
+        std::mem::swap(&mut clist, &mut nlist);
+        nlist.clear();
+    }
+
+    Ok(matched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::eval_width;
+    use crate::engine::evaluator::num_slots;
+    use crate::engine::{codegen::get_code, parser::parse};
+
+    #[test]
+    fn test_eval_width() {
+        let eval = |expr: &str, line: &str| {
+            let code = get_code(&parse(expr).unwrap()).unwrap();
+            let line = line.chars().collect::<Vec<char>>();
+            let mut slots = vec![None; num_slots(&code)];
+            let matched = eval_width(&code, &line, 0, false, &mut slots).unwrap();
+            slots.truncate(4);
+            matched.then_some(slots)
+        };
+
+        assert_eq!(eval("a(b|bc)d", "xabcd"), Some(vec![Some(1), Some(5), Some(2), Some(4)]));
+        assert_eq!(eval("a(b*)", "abbab"), Some(vec![Some(0), Some(3), Some(1), Some(3)]));
+        assert_eq!(eval("(a|ab)", "ab"), Some(vec![Some(0), Some(1), Some(0), Some(1)]));
+        assert_eq!(eval("(a*)*", "aab"), Some(vec![Some(0), Some(2), Some(0), Some(2)]));
+        assert_eq!(eval("ab", "aac"), None);
+
+        // バックトラックでは指数時間かかる (a?){30}a{30}
+        let expr = format!("{}{}", "(a?)".repeat(30), "a".repeat(30));
+        let code = get_code(&parse(&expr).unwrap()).unwrap();
+        let line = "a".repeat(30).chars().collect::<Vec<char>>();
+        let mut slots = vec![None; 62];
+        assert!(eval_width(&code, &line, 0, true, &mut slots).unwrap());
+        assert_eq!(slots[1], Some(30));
+    }
+}
@@ -2,19 +2,76 @@
 use std::{
     error::Error,
     fmt::{self, Display},
-    mem::take
+    iter::Peekable,
+    mem::take,
+    sync::Arc
 };
 use std::fmt::{Formatter};
+use super::unicode_tables::{self, CASE_FOLDING_SIMPLE, PERL_DIGIT, PERL_SPACE, PERL_WORD};
 
 // 中小構文木を表現するための型
 #[derive(Debug, PartialEq)]
 pub enum AST {
     Char(char),
-    Plus(Box<AST>),
-    Star(Box<AST>),
-    Question(Box<AST>),
+    Plus(Box<AST>, bool), // 繰り返しの対象と、貪欲かどうか
+    Star(Box<AST>, bool),
+    Question(Box<AST>, bool),
     Or(Box<AST>, Box<AST>),
     Seq(Vec<AST>),
+    Capture(usize, Option<String>, Box<AST>), // 番号と名前付きのキャプチャグループ
+    Class(Arc<[(char, char)]>, bool), // 文字クラス。昇順で重ならない範囲の列と否定の有無
+    Any(bool), // 任意の1文字。改行にもマッチするか
+    Assert(Look), // 幅0の位置の検査
+    Backref(usize, bool), // 後方参照。参照するグループの番号と、大文字と小文字を区別しないか
+    LookAround {
+        expr: Box<AST>,
+        behind: bool,  // 後読みかどうか
+        negated: bool, // 否定かどうか
+    },
+    Atomic(Box<AST>), // マッチした後は中の分岐に戻らないグループ
+    Repeat {
+        expr: Box<AST>,
+        min: usize,
+        max: Option<usize>, // Noneの場合は上限なし
+        greedy: bool,
+    },
+}
+
+/// 幅0で位置を検査する表明の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Look {
+    StartText,              // \A, ^
+    EndText,                // \z, $
+    StartLine,              // 複数行モードの^
+    EndLine,                // 複数行モードの$
+    WordBoundary,           // \b
+    NotWordBoundary,        // \B
+    WordBoundaryUnicode,    // Unicodeモードの\b
+    NotWordBoundaryUnicode, // Unicodeモードの\B
+}
+
+/// 正規表現の構文の種類
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Syntax {
+    /// 標準の構文。`(?...)`による拡張と、非貪欲・強欲な繰り返しを使える
+    #[default]
+    Standard,
+    /// POSIX拡張正規表現（ERE）。`(?...)`と、`*?`や`*+`のような接尾辞を使えない
+    PosixExtended,
+    /// POSIX基本正規表現（BRE）。`\(`, `\)`, `\{`, `\}`, `\|`, `\+`, `\?`が演算子となり、
+    /// エスケープしない`(`, `)`, `{`, `}`, `|`, `+`, `?`は通常の文字となる
+    PosixBasic,
+}
+
+/// パース時に有効なフラグ
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags {
+    pub dot_all: bool,          // s: `.`が改行にもマッチする
+    pub multi_line: bool,       // m: `^`と`$`が行頭と行末にマッチする
+    pub unicode: bool,          // u: `\d`, `\w`, `\s`がUnicodeの定義に従う
+    pub case_insensitive: bool, // i: 大文字と小文字を区別しない
+    pub extended: bool,         // x: 空白と`#`から行末までのコメントを無視する
+    pub syntax: Syntax,         // 構文の種類
 }
 
 #[derive(Debug)]
@@ -23,6 +80,19 @@ pub enum ParseError {
     InvalidRightParen(usize),
     NoPrev(usize),
     NoRightParen,
+    NoRightBracket,
+    InvalidRange(usize, char, char),
+    UnknownFlag(usize, char),
+    InvalidRepeat(usize),
+    InvalidHex(usize),
+    InvalidCodePoint(usize, u32),
+    InvalidGroupName(usize),
+    DuplicateGroupName(usize, String),
+    InvalidBackref(usize),
+    SelfReferencingBackref(usize, usize),
+    UnknownProperty(usize, String),
+    UnknownPosixClass(usize, String),
+    EmptyClassOperand(usize),
     Empty,
 }
 
@@ -42,6 +112,48 @@ impl Display for ParseError {
             ParseError::NoRightParen => {
                 write!(f, "ParseError: no right parenthesis")
             }
+            ParseError::NoRightBracket => {
+                write!(f, "ParseError: no right bracket")
+            }
+            ParseError::InvalidRange(pos, start, end) => {
+                write!(f, "ParseError: invalid range: pos = {pos}, range = '{start}-{end}'")
+            }
+            ParseError::UnknownFlag(pos, c) => {
+                write!(f, "ParseError: unknown flag: pos = {pos}, char = '{c}'")
+            }
+            ParseError::InvalidRepeat(pos) => {
+                write!(f, "ParseError: invalid repetition: pos = {pos}")
+            }
+            ParseError::InvalidHex(pos) => {
+                write!(f, "ParseError: invalid hexadecimal escape: pos = {pos}")
+            }
+            ParseError::InvalidCodePoint(pos, n) => {
+                write!(f, "ParseError: invalid code point: pos = {pos}, code point = {n:#x}")
+            }
+            ParseError::InvalidGroupName(pos) => {
+                write!(f, "ParseError: invalid group name: pos = {pos}")
+            }
+            ParseError::DuplicateGroupName(pos, name) => {
+                write!(f, "ParseError: duplicate group name: pos = {pos}, name = '{name}'")
+            }
+            ParseError::InvalidBackref(pos) => {
+                write!(f, "ParseError: invalid backreference: pos = {pos}")
+            }
+            ParseError::SelfReferencingBackref(pos, n) => {
+                write!(
+                    f,
+                    "ParseError: backreference inside the group it refers to: pos = {pos}, group = {n}"
+                )
+            }
+            ParseError::UnknownProperty(pos, name) => {
+                write!(f, "ParseError: unknown Unicode property: pos = {pos}, name = '{name}'")
+            }
+            ParseError::UnknownPosixClass(pos, name) => {
+                write!(f, "ParseError: unknown POSIX class: pos = {pos}, name = '{name}'")
+            }
+            ParseError::EmptyClassOperand(pos) => {
+                write!(f, "ParseError: empty operand of class set operation: pos = {pos}")
+            }
             ParseError::Empty => {
                 write!(f, "ParseError: no right parenthesis")
             }
@@ -51,9 +163,298 @@ impl Display for ParseError {
 
 impl Error for ParseError {}
 
-fn parse_escape(pos: usize, c: char) -> Result<AST, ParseError> {
+/// 定義済みの文字クラスの範囲
+type StaticRanges = &'static [(char, char)];
+
+/// `\d`, `\w`, `\s`とその否定`\D`, `\W`, `\S`の範囲と、否定かどうかを返す
+///
+/// `unicode`が偽の場合はASCIIの範囲のみを対象とする。
+fn perl_class(c: char, unicode: bool) -> Option<(StaticRanges, bool)> {
+    let ranges: &[(char, char)] = match (c.to_ascii_lowercase(), unicode) {
+        ('d', false) => &[('0', '9')],
+        ('d', true) => PERL_DIGIT,
+        ('s', false) => &[('\t', '\r'), (' ', ' ')], // \t, \n, \v, \f, \r, 空白
+        ('s', true) => PERL_SPACE,
+        ('w', false) => &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')],
+        ('w', true) => PERL_WORD,
+        _ => return None,
+    };
+    Some((ranges, c.is_ascii_uppercase()))
+}
+
+/// `\p`または`\P`に続くUnicodeプロパティ`{name}`または1文字の名前をパースし、範囲を返す
+fn parse_property<I>(chars: &mut Peekable<I>, pos: usize) -> Result<StaticRanges, ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    let mut name = String::new();
+    if chars.next_if(|(_, c)| *c == '{').is_some() {
+        loop {
+            match chars.next() {
+                Some((_, '}')) => break,
+                Some((_, c)) => name.push(c),
+                None => return Err(ParseError::UnknownProperty(pos, name)),
+            }
+        }
+    } else if let Some((_, c)) = chars.next() {
+        name.push(c);
+    }
+    unicode_tables::property(&name).ok_or(ParseError::UnknownProperty(pos, name))
+}
+
+/// `[:name:]`の形式で書くPOSIX文字クラスの範囲を返す
+///
+/// いずれもASCIIの範囲のみを対象とする。
+fn posix_class(name: &str) -> Option<StaticRanges> {
+    let ranges: StaticRanges = match name {
+        "alnum" => &[('0', '9'), ('A', 'Z'), ('a', 'z')],
+        "alpha" => &[('A', 'Z'), ('a', 'z')],
+        "ascii" => &[('\0', '\x7f')],
+        "blank" => &[('\t', '\t'), (' ', ' ')],
+        "cntrl" => &[('\0', '\x1f'), ('\x7f', '\x7f')],
+        "digit" => &[('0', '9')],
+        "graph" => &[('!', '~')],
+        "lower" => &[('a', 'z')],
+        "print" => &[(' ', '~')],
+        "punct" => &[('!', '/'), (':', '@'), ('[', '`'), ('{', '~')],
+        "space" => &[('\t', '\r'), (' ', ' ')],
+        "upper" => &[('A', 'Z')],
+        "word" => &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')],
+        "xdigit" => &[('0', '9'), ('A', 'F'), ('a', 'f')],
+        _ => return None,
+    };
+    Some(ranges)
+}
+
+/// 文字クラス内の`[:`以降をパースし、POSIX文字クラスの範囲と否定かどうかを返す
+///
+/// `[:^name:]`は否定を表す。
+fn parse_posix_class<I>(chars: &mut Peekable<I>, pos: usize) -> Result<(StaticRanges, bool), ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    let negated = chars.next_if(|(_, c)| *c == '^').is_some();
+    let mut name = String::new();
+    while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_alphabetic()) {
+        name.push(c);
+    }
+    let closed = chars.next_if(|(_, c)| *c == ':').is_some()
+        && chars.next_if(|(_, c)| *c == ']').is_some();
+    match posix_class(&name) {
+        Some(ranges) if closed => Ok((ranges, negated)),
+        _ => Err(ParseError::UnknownPosixClass(pos, name)),
+    }
+}
+
+/// 範囲の集合として表せるエスケープの文字か
+fn is_class_escape(c: char) -> bool {
+    matches!(c, 'd' | 'D' | 'w' | 'W' | 's' | 'S' | 'p' | 'P')
+}
+
+/// `\d`, `\w`, `\s`, `\p{..}`とその否定をパースし、範囲と否定かどうかを返す
+fn parse_class_set<I>(
+    chars: &mut Peekable<I>,
+    pos: usize,
+    c: char,
+    flags: Flags,
+) -> Result<(StaticRanges, bool), ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    match c {
+        'p' | 'P' => Ok((parse_property(chars, pos)?, c == 'P')),
+        _ => perl_class(c, flags.unicode).ok_or(ParseError::InvalidEscape(pos, c)),
+    }
+}
+
+/// 昇順に並んだ重ならない範囲の補集合を返す（サロゲートは除く）
+fn negate_ranges(ranges: &[(char, char)]) -> Vec<(char, char)> {
+    let next_char = |c: char| match c {
+        '\u{d7ff}' => Some('\u{e000}'),
+        _ => char::from_u32(c as u32 + 1),
+    };
+    let prev_char = |c: char| match c {
+        '\u{e000}' => '\u{d7ff}',
+        _ => char::from_u32(c as u32 - 1).unwrap(),
+    };
+
+    let mut negated = Vec::new();
+    let mut next = Some('\0');
+    for &(start, end) in ranges {
+        if let Some(n) = next.filter(|n| *n < start) {
+            negated.push((n, prev_char(start)));
+        }
+        next = next_char(end);
+    }
+    if let Some(n) = next {
+        negated.push((n, char::MAX));
+    }
+    negated
+}
+
+/// `\x`と`\u`に続く16進数の符号位置を読む
+///
+/// `\xHH`は2桁ちょうど、`\u{H...}`は1〜6桁。
+fn parse_code_point<I>(chars: &mut Peekable<I>, pos: usize, braced: bool) -> Result<char, ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    if braced && chars.next_if(|(_, c)| *c == '{').is_none() {
+        return Err(ParseError::InvalidHex(pos));
+    }
+
+    let max_digits = if braced { 6 } else { 2 };
+    let mut n = 0;
+    let mut digits = 0;
+    while digits < max_digits {
+        if let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_hexdigit()) {
+            n = n * 16 + c.to_digit(16).unwrap();
+            digits += 1;
+        } else {
+            break;
+        }
+    }
+
+    let closed = if braced {
+        digits > 0 && chars.next_if(|(_, c)| *c == '}').is_some()
+    } else {
+        digits == max_digits
+    };
+    if !closed {
+        return Err(ParseError::InvalidHex(pos));
+    }
+    char::from_u32(n).ok_or(ParseError::InvalidCodePoint(pos, n))
+}
+
+/// 文字を表すエスケープ`\n`, `\t`, `\r`, `\f`, `\v`, `\0`, `\xHH`, `\u{H...}`を読む
+///
+/// 該当しないエスケープの場合は`None`を返す。
+fn parse_char_escape<I>(chars: &mut Peekable<I>, pos: usize, c: char) -> Result<Option<char>, ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    let c = match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        'f' => '\x0c',
+        'v' => '\x0b',
+        '0' => '\0',
+        'x' => parse_code_point(chars, pos, false)?,
+        'u' => parse_code_point(chars, pos, true)?,
+        _ => return Ok(None),
+    };
+    Ok(Some(c))
+}
+
+/// 昇順に並べ、重なるか隣接する範囲をまとめる
+pub fn normalize_ranges(mut ranges: Vec<(char, char)>) -> Vec<(char, char)> {
+    ranges.sort_unstable();
+    let mut normalized: Vec<(char, char)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match normalized.last_mut() {
+            Some((_, last)) if start as u32 <= *last as u32 + 1 => *last = end.max(*last),
+            _ => normalized.push((start, end)),
+        }
+    }
+    normalized
+}
+
+/// 範囲に含まれる文字と単純ケースフォールディングで同一視される文字を加える
+fn fold_ranges(ranges: Vec<(char, char)>) -> Vec<(char, char)> {
+    let mut folded = ranges.clone();
+    for &(start, end) in &ranges {
+        let lo = CASE_FOLDING_SIMPLE.partition_point(|(c, _)| *c < start);
+        let hi = CASE_FOLDING_SIMPLE.partition_point(|(c, _)| *c <= end);
+        for (_, folds) in &CASE_FOLDING_SIMPLE[lo..hi] {
+            folded.extend(folds.iter().map(|&f| (f, f)));
+        }
+    }
+    normalize_ranges(folded)
+}
+
+/// 大文字と小文字を区別しない場合、文字と文字クラスを同一視される文字を含むクラスに変換
+fn apply_case(ast: AST, flags: Flags) -> AST {
+    if !flags.case_insensitive {
+        return ast;
+    }
+
+    match ast {
+        AST::Char(c) => {
+            let folded = fold_ranges(vec![(c, c)]);
+            if folded.len() == 1 && folded[0] == (c, c) {
+                AST::Char(c)
+            } else {
+                AST::Class(folded.into(), false)
+            }
+        }
+        AST::Class(ranges, negated) => AST::Class(fold_ranges(ranges.to_vec()).into(), negated),
+        ast => ast,
+    }
+}
+
+/// 標準の構文で特別な意味を持ち、`\`でエスケープする文字か
+///
+/// 空白と`#`は、空白とコメントを無視する場合に特別な意味を持つ。
+fn is_meta_char(c: char) -> bool {
+    matches!(
+        c,
+        '\\' | '(' | ')' | '|' | '+' | '*' | '?' | '[' | ']' | '.' | '^' | '$' | '{' | '}' | ' '
+            | '#'
+    )
+}
+
+/// 文字列中の特別な意味を持つ文字をエスケープし、その文字列そのものにマッチするパターンを返す
+///
+/// 返り値を標準の構文でパースすると、空白とコメントを無視する場合も含め、元の文字列の各文字からなる列になる。
+/// 空文字列に対しては、空の列になる`(?:)`を返す。
+///
+/// ```
+/// use reg::Regex;
+/// let re = Regex::new(&reg::escape("1+1=2?")).unwrap();
+/// assert!(re.is_match("1+1=2?").unwrap());
+/// assert!(!re.is_match("11=2").unwrap());
+/// ```
+pub fn escape(text: &str) -> String {
+    if text.is_empty() {
+        return "(?:)".to_string();
+    }
+
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if is_meta_char(c) {
+            escaped.push('\\');
+            escaped.push(c);
+        } else if c.is_whitespace() {
+            // 空白とコメントを無視する場合に読み飛ばされないよう、コードポイントで書く
+            escaped.push_str(&format!("\\u{{{:x}}}", c as u32));
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+fn parse_escape<I>(chars: &mut Peekable<I>, pos: usize, c: char, flags: Flags) -> Result<AST, ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    if is_class_escape(c) {
+        let (ranges, negated) = parse_class_set(chars, pos, c, flags)?;
+        return Ok(AST::Class(ranges.into(), negated));
+    }
+    if let Some(c) = parse_char_escape(chars, pos, c)? {
+        return Ok(AST::Char(c));
+    }
+
     match c {
-        '\\' | '(' | ')' | '|' | '+' | '*' | '?' => Ok(AST::Char(c)),
+        c if is_meta_char(c) => Ok(AST::Char(c)),
+        'A' => Ok(AST::Assert(Look::StartText)),
+        'z' => Ok(AST::Assert(Look::EndText)),
+        'b' if flags.unicode => Ok(AST::Assert(Look::WordBoundaryUnicode)),
+        'b' => Ok(AST::Assert(Look::WordBoundary)),
+        'B' if flags.unicode => Ok(AST::Assert(Look::NotWordBoundaryUnicode)),
+        'B' => Ok(AST::Assert(Look::NotWordBoundary)),
         _ => {
             let err = ParseError::InvalidEscape(pos, c);
             Err(err)
@@ -61,25 +462,361 @@ fn parse_escape(pos: usize, c: char) -> Result<AST, ParseError> {
     }
 }
 
+/// 文字クラス内のエスケープ
+fn parse_class_escape<I>(chars: &mut Peekable<I>, pos: usize, c: char) -> Result<char, ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    if let Some(c) = parse_char_escape(chars, pos, c)? {
+        return Ok(c);
+    }
+
+    match c {
+        c if is_meta_char(c) || c == '-' => Ok(c),
+        _ => Err(ParseError::InvalidEscape(pos, c)),
+    }
+}
+
+/// 文字クラスの要素を1文字読む
+///
+/// `escapes`が偽の場合、`\`は通常の文字として扱う。
+fn parse_class_char<I>(chars: &mut Peekable<I>, c: char, escapes: bool) -> Result<char, ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    if escapes && c == '\\' {
+        let (i, c) = chars.next().ok_or(ParseError::NoRightBracket)?;
+        parse_class_escape(chars, i, c)
+    } else {
+        Ok(c)
+    }
+}
+
+/// 定義済みの文字クラスの範囲を追加する
+///
+/// 大文字と小文字を区別しない場合、否定は同一視される文字を加えてから行う。
+fn extend_class(
+    ranges: &mut Vec<(char, char)>,
+    class: &[(char, char)],
+    negated: bool,
+    flags: Flags,
+) {
+    if !negated {
+        ranges.extend_from_slice(class);
+    } else if flags.case_insensitive {
+        ranges.extend(negate_ranges(&fold_ranges(class.to_vec())));
+    } else {
+        ranges.extend(negate_ranges(class));
+    }
+}
+
+/// 文字クラスの集合演算
+#[derive(Debug, Clone, Copy)]
+enum ClassOp {
+    Intersection, // &&
+    Difference,   // --
+}
+
+/// 昇順に並んだ重ならない範囲の積集合を返す
+fn intersect_ranges(a: &[(char, char)], b: &[(char, char)]) -> Vec<(char, char)> {
+    let mut intersection = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while let (Some(&(s1, e1)), Some(&(s2, e2))) = (a.get(i), b.get(j)) {
+        let (start, end) = (s1.max(s2), e1.min(e2));
+        if start <= end {
+            intersection.push((start, end));
+        }
+        if e1 < e2 {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    intersection
+}
+
+/// 集合演算の左辺と演算子があれば右辺`rhs`との演算結果を、なければ`rhs`を正規化して返す
+///
+/// 大文字と小文字を区別しない場合、`rhs`には同一視される文字を加えてから演算する。
+fn apply_class_op(
+    lhs: Option<(Vec<(char, char)>, ClassOp)>,
+    rhs: Vec<(char, char)>,
+    flags: Flags,
+) -> Vec<(char, char)> {
+    let rhs = if flags.case_insensitive {
+        fold_ranges(rhs)
+    } else {
+        normalize_ranges(rhs)
+    };
+    match lhs {
+        None => rhs,
+        Some((lhs, ClassOp::Intersection)) => intersect_ranges(&lhs, &rhs),
+        Some((lhs, ClassOp::Difference)) => intersect_ranges(&lhs, &negate_ranges(&rhs)),
+    }
+}
+
+/// `[`以降の文字クラスをパース
+///
+/// 先頭の`]`と、先頭または末尾の`-`は通常の文字として扱う。
+/// `\d`や`[:alpha:]`などの定義済みクラスは範囲の端点には使えない。
+/// 範囲は昇順で重ならないように正規化し、大文字と小文字を区別しない場合は同一視される文字を含める。
+fn parse_class<I>(chars: &mut Peekable<I>, flags: Flags) -> Result<AST, ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    let negated = chars.next_if(|(_, c)| *c == '^').is_some();
+    let ranges = parse_class_body(chars, flags)?;
+    Ok(AST::Class(ranges.into(), negated))
+}
+
+/// `[`または`[^`以降の文字クラスを`]`までパースし、正規化した範囲を返す
+///
+/// 標準の構文では`[...]`を入れ子にでき、`&&`は積集合、`--`は差集合を表す。
+/// 集合演算は左結合で、和集合より優先順位が低い。
+/// POSIXの構文では`\`はエスケープにならず、通常の文字として扱う。
+fn parse_class_body<I>(
+    chars: &mut Peekable<I>,
+    flags: Flags,
+) -> Result<Vec<(char, char)>, ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    let standard = flags.syntax == Syntax::Standard; // 集合演算、入れ子、エスケープを使えるか
+    let mut lhs = None; // 集合演算の左辺と演算子
+    let mut ranges = Vec::new();
+    let mut empty = true; // 集合演算の被演算子が空か
+
+    loop {
+        let (i, c) = chars.next().ok_or(ParseError::NoRightBracket)?;
+        if c == ']' && !empty {
+            return Ok(apply_class_op(lhs, ranges, flags));
+        }
+
+        if standard && (c == '&' || c == '-') && chars.next_if(|(_, d)| *d == c).is_some() {
+            if empty {
+                return Err(ParseError::EmptyClassOperand(i));
+            }
+            let op = if c == '&' {
+                ClassOp::Intersection
+            } else {
+                ClassOp::Difference
+            };
+            lhs = Some((apply_class_op(lhs, take(&mut ranges), flags), op));
+            empty = true;
+            continue;
+        }
+        empty = false;
+
+        if standard && c == '\\' {
+            if let Some((j, e)) = chars.next_if(|(_, e)| is_class_escape(*e)) {
+                let (class, class_negated) = parse_class_set(chars, j, e, flags)?;
+                extend_class(&mut ranges, class, class_negated, flags);
+                continue;
+            }
+        }
+
+        if c == '[' && chars.next_if(|(_, c)| *c == ':').is_some() {
+            let (class, class_negated) = parse_posix_class(chars, i)?;
+            extend_class(&mut ranges, class, class_negated, flags);
+            continue;
+        }
+
+        if standard && c == '[' {
+            let nested_negated = chars.next_if(|(_, c)| *c == '^').is_some();
+            let nested = parse_class_body(chars, flags)?;
+            extend_class(&mut ranges, &nested, nested_negated, flags);
+            continue;
+        }
+
+        let start = parse_class_char(chars, c, standard)?;
+        if chars.next_if(|(_, c)| *c == '-').is_none() {
+            ranges.push((start, start));
+            continue;
+        }
+
+        let (j, c) = chars.next().ok_or(ParseError::NoRightBracket)?;
+        if c == ']' {
+            ranges.push((start, start));
+            ranges.push(('-', '-'));
+            return Ok(apply_class_op(lhs, ranges, flags));
+        }
+        if standard && c == '-' {
+            // `a--b`は差集合
+            ranges.push((start, start));
+            lhs = Some((apply_class_op(lhs, take(&mut ranges), flags), ClassOp::Difference));
+            empty = true;
+            continue;
+        }
+
+        let end = parse_class_char(chars, c, standard)?;
+        if start > end {
+            return Err(ParseError::InvalidRange(i.min(j), start, end));
+        }
+        ranges.push((start, end));
+    }
+}
+
+/// `(?`以降のフラグを`)`または`:`までパースし、`flags`に設定
+///
+/// `-`より後のフラグは無効にする。`:`で終わる場合は真を返す。
+fn parse_flags<I>(chars: &mut Peekable<I>, flags: &mut Flags) -> Result<bool, ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    let mut enable = true;
+    loop {
+        let (i, c) = chars.next().ok_or(ParseError::NoRightParen)?;
+        let flag = match c {
+            ')' => return Ok(false),
+            ':' => return Ok(true),
+            '-' if enable => {
+                enable = false;
+                continue;
+            }
+            'i' => &mut flags.case_insensitive,
+            'm' => &mut flags.multi_line,
+            's' => &mut flags.dot_all,
+            'u' => &mut flags.unicode,
+            'x' => &mut flags.extended,
+            _ => return Err(ParseError::UnknownFlag(i, c)),
+        };
+        *flag = enable;
+    }
+}
+
+/// `(?P<`または`(?<`以降のグループ名を`>`までパース
+///
+/// 名前は英字か`_`で始まり、英数字と`_`からなる。
+fn parse_group_name<I>(chars: &mut Peekable<I>, pos: usize) -> Result<String, ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    let mut name = String::new();
+    loop {
+        match chars.next() {
+            Some((_, '>')) if !name.is_empty() => return Ok(name),
+            Some((_, c)) if c.is_ascii_alphabetic() || c == '_' => name.push(c),
+            Some((_, c)) if c.is_ascii_digit() && !name.is_empty() => name.push(c),
+            _ => return Err(ParseError::InvalidGroupName(pos)),
+        }
+    }
+}
+
+/// `\`に続く後方参照`\n`, `\k<name>`をパースし、参照するグループの番号を返す
+///
+/// 名前による参照は、それより前に定義されたグループのみを参照できる。
+/// 番号による参照の範囲は、パースの終了後に検査する。
+fn parse_backref<I>(
+    chars: &mut Peekable<I>,
+    pos: usize,
+    c: char,
+    names: &[(String, usize)],
+) -> Result<usize, ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    if c == 'k' {
+        if chars.next_if(|(_, c)| *c == '<').is_none() {
+            return Err(ParseError::InvalidBackref(pos));
+        }
+        let name = parse_group_name(chars, pos)?;
+        return names
+            .iter()
+            .find_map(|(n, group)| (*n == name).then_some(*group))
+            .ok_or(ParseError::InvalidBackref(pos));
+    }
+
+    let mut n = c as usize - '0' as usize;
+    while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+        let d = c as usize - '0' as usize;
+        n = n.checked_mul(10).and_then(|n| n.checked_add(d)).ok_or(ParseError::InvalidBackref(pos))?;
+    }
+    Ok(n)
+}
+
+/// 10進数を読む
+fn parse_decimal<I>(chars: &mut Peekable<I>, pos: usize) -> Result<Option<usize>, ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    let mut n: Option<usize> = None;
+    while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+        let d = c as usize - '0' as usize;
+        let m = n.unwrap_or(0).checked_mul(10).and_then(|m| m.checked_add(d));
+        n = Some(m.ok_or(ParseError::InvalidRepeat(pos))?);
+    }
+    Ok(n)
+}
+
+/// `{`以降の回数指定`n}`, `n,}`, `n,m}`をパースし、最小回数と最大回数を返す
+fn parse_repeat<I>(chars: &mut Peekable<I>, pos: usize) -> Result<(usize, Option<usize>), ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    let min = parse_decimal(chars, pos)?.ok_or(ParseError::InvalidRepeat(pos))?;
+    let max = if chars.next_if(|(_, c)| *c == ',').is_some() {
+        parse_decimal(chars, pos)?
+    } else {
+        Some(min)
+    };
+
+    if chars.next_if(|(_, c)| *c == '}').is_none() || max.is_some_and(|max| max < min) {
+        return Err(ParseError::InvalidRepeat(pos));
+    }
+    Ok((min, max))
+}
+
 enum PSQ {
     Plus,
     Star,
     Question
 }
 
+/// 繰り返しの直後の`?`と`+`を読み、貪欲かどうかと強欲かどうかを返す
+///
+/// `?`が続く場合は貪欲でない（最短一致の）繰り返し、`+`が続く場合は
+/// バックトラックで戻らない強欲な繰り返しとする。
+fn parse_greedy<I>(chars: &mut Peekable<I>, flags: Flags) -> (bool, bool)
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    if flags.syntax != Syntax::Standard {
+        (true, false)
+    } else if chars.next_if(|(_, c)| *c == '?').is_some() {
+        (false, false)
+    } else {
+        (true, chars.next_if(|(_, c)| *c == '+').is_some())
+    }
+}
+
+/// 強欲な繰り返しをアトミックグループで囲む
+fn possessive(ast: AST, possessive: bool) -> AST {
+    if possessive {
+        AST::Atomic(Box::new(ast))
+    } else {
+        ast
+    }
+}
+
 // +, *, ?の処理
-fn parse_plus_start_question(
+fn parse_plus_start_question<I>(
     seq: &mut Vec<AST>,
     ast_type: PSQ,
     pos: usize,
-) -> Result<(), ParseError> {
+    chars: &mut Peekable<I>,
+    flags: Flags,
+) -> Result<(), ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
     if let Some(prev) = seq.pop() {
+        let (greedy, is_possessive) = parse_greedy(chars, flags);
         let ast = match ast_type {
-            PSQ::Plus => AST::Plus(Box::new(prev)),
-            PSQ::Star => AST::Star(Box::new(prev)),
-            PSQ::Question => AST::Question(Box::new(prev)),
+            PSQ::Plus => AST::Plus(Box::new(prev), greedy),
+            PSQ::Star => AST::Star(Box::new(prev), greedy),
+            PSQ::Question => AST::Question(Box::new(prev), greedy),
         };
-        seq.push(ast);
+        seq.push(possessive(ast, is_possessive));
         Ok(())
     } else {
         Err(ParseError::NoPrev(pos))
@@ -99,52 +836,234 @@ fn fold_or(mut seq_or: Vec<AST>) -> Option<AST> {
     }
 }
 
+/// 位置`start`の`[`から始まる文字クラスの終わりの次の位置を返す
+///
+/// 括弧式の中の`\`は通常の文字であり、`]`をエスケープしない。
+fn bracket_end(chars: &[char], start: usize) -> usize {
+    let mut i = start + 1;
+    if chars.get(i) == Some(&'^') {
+        i += 1;
+    }
+    if chars.get(i) == Some(&']') {
+        i += 1;
+    }
+    while let Some(c) = chars.get(i) {
+        match c {
+            ']' => return i + 1,
+            '[' if chars.get(i + 1) == Some(&':') => {
+                i += 2;
+                while chars.get(i).is_some_and(|c| *c != ']') {
+                    i += 1;
+                }
+                i += 1;
+            }
+            _ => i += 1,
+        }
+    }
+    chars.len()
+}
+
+/// POSIX基本正規表現を標準の構文の文字の列に変換
+///
+/// `*`はパターン、グループ、選択肢の先頭では通常の文字となる。
+/// `^`は先頭でのみ、`$`は末尾と`\)`、`\|`の直前でのみアンカーとなる。
+/// 各文字の位置は元のパターン中の位置を保つ。
+fn translate_bre(expr: &str) -> Vec<(usize, char)> {
+    let chars: Vec<char> = expr.chars().collect();
+    let mut tokens = Vec::new();
+    let mut at_start = true; // パターン、グループ、選択肢の先頭か
+    let mut i = 0;
+    while let Some(&c) = chars.get(i) {
+        let start = at_start;
+        at_start = false;
+        match (c, chars.get(i + 1)) {
+            ('\\', Some(&e @ ('(' | '|'))) => {
+                tokens.push((i, e));
+                at_start = true;
+                i += 1;
+            }
+            ('\\', Some(&e @ (')' | '{' | '}' | '+' | '?'))) => {
+                tokens.push((i, e));
+                i += 1;
+            }
+            ('\\', Some(&e)) => {
+                tokens.extend([(i, c), (i + 1, e)]);
+                i += 1;
+            }
+            ('(' | ')' | '{' | '}' | '|' | '+' | '?', _) => tokens.extend([(i, '\\'), (i, c)]),
+            ('*', _) if start => tokens.extend([(i, '\\'), (i, c)]),
+            ('^', _) if start => {
+                tokens.push((i, c));
+                at_start = true;
+            }
+            ('^', _) => tokens.extend([(i, '\\'), (i, c)]),
+            ('$', next) => {
+                let end = match next {
+                    None => true,
+                    Some('\\') => matches!(chars.get(i + 2), Some(')' | '|')),
+                    _ => false,
+                };
+                if end {
+                    tokens.push((i, c));
+                } else {
+                    tokens.extend([(i, '\\'), (i, c)]);
+                }
+            }
+            ('[', _) => {
+                let end = bracket_end(&chars, i);
+                tokens.extend((i..end).map(|j| (j, chars[j])));
+                i = end - 1;
+            }
+            _ => tokens.push((i, c)),
+        }
+        i += 1;
+    }
+    tokens
+}
+
 /// 正規表現を抽象構文木に変換
 pub fn parse(expr: &str) -> Result<AST, Box<ParseError>> {
+    parse_with_flags(expr, Flags::default())
+}
+
+/// フラグの初期値を指定して正規表現を抽象構文木に変換
+///
+/// `(?s)`のようにパターン中で設定したフラグは、それを囲むグループの終わりまで有効。
+/// `(?s:...)`のように`:`を付けた場合は、そのグループの中でのみ有効。
+pub fn parse_with_flags(expr: &str, mut flags: Flags) -> Result<AST, Box<ParseError>> {
     enum ParseState {
         Char,
         Escape,
+        Quote, // \Qから\Eまで
+    }
+
+    // 閉じ括弧で生成する式の種類
+    enum Group {
+        Capture(usize, Option<String>),
+        NonCapture,
+        LookAround { behind: bool, negated: bool },
+        Atomic,
     }
 
     let mut seq = Vec::new();
     let mut seq_or = Vec::new();
     let mut stack = Vec::new();
     let mut state = ParseState::Char;
+    let mut group = 0; // 直前に開いたキャプチャグループの番号
+    let mut names = Vec::new(); // 定義済みのグループ名と番号
+    let mut backrefs = Vec::new(); // 番号による後方参照の位置と番号
 
-    for (i, c) in expr.chars().enumerate() {
+    let tokens = if flags.syntax == Syntax::PosixBasic {
+        translate_bre(expr)
+    } else {
+        expr.chars().enumerate().collect()
+    };
+    let mut chars = tokens.into_iter().peekable();
+    while let Some((i, c)) = chars.next() {
         match &state {
             ParseState::Char => {
                 match c {
                     '+' => parse_plus_start_question(
                         &mut seq,
                         PSQ::Plus,
-                        i
+                        i,
+                        &mut chars,
+                        flags
                     )?,
                     '*' => parse_plus_start_question(
                         &mut seq,
                         PSQ::Star,
-                        i
+                        i,
+                        &mut chars,
+                        flags
                     )?,
                     '?' => parse_plus_start_question(
                         &mut seq,
                         PSQ::Question,
-                        i
+                        i,
+                        &mut chars,
+                        flags
                     )?,
+                    // 数字が続かない`{`は通常の文字として扱う
+                    '{' if chars.peek().is_some_and(|(_, c)| c.is_ascii_digit()) => {
+                        let (min, max) = parse_repeat(&mut chars, i)?;
+                        let prev = seq.pop().ok_or(ParseError::NoPrev(i))?;
+                        let (greedy, is_possessive) = parse_greedy(&mut chars, flags);
+                        let ast = AST::Repeat {
+                            expr: Box::new(prev),
+                            min,
+                            max,
+                            greedy,
+                        };
+                        seq.push(possessive(ast, is_possessive));
+                    },
+                    c if flags.extended && c.is_whitespace() => (),
+                    '#' if flags.extended => {
+                        while chars.next_if(|(_, c)| *c != '\n').is_some() {}
+                    },
+                    '(' if flags.syntax == Syntax::Standard
+                        && chars.next_if(|(_, c)| *c == '?').is_some() =>
+                    {
+                        let outer_flags = flags;
+                        let named = chars.next_if(|(_, c)| *c == 'P').is_some();
+                        let angle = chars.next_if(|(_, c)| *c == '<').is_some();
+                        let atomic = !named && !angle && chars.next_if(|(_, c)| *c == '>').is_some();
+                        let kind = if atomic {
+                            Group::Atomic
+                        } else if let Some((_, c)) =
+                            chars.next_if(|(_, c)| !named && (*c == '=' || *c == '!'))
+                        {
+                            // (?=...), (?!...), (?<=...), (?<!...)
+                            Group::LookAround {
+                                behind: angle,
+                                negated: c == '!',
+                            }
+                        } else if angle {
+                            let name = parse_group_name(&mut chars, i)?;
+                            if names.iter().any(|(n, _)| *n == name) {
+                                return Err(Box::new(ParseError::DuplicateGroupName(i, name)));
+                            }
+                            group += 1;
+                            names.push((name.clone(), group));
+                            Group::Capture(group, Some(name))
+                        } else if named {
+                            return Err(Box::new(ParseError::InvalidGroupName(i)));
+                        } else if parse_flags(&mut chars, &mut flags)? {
+                            // (?flags:...)のフラグはグループの中でのみ有効
+                            Group::NonCapture
+                        } else {
+                            continue;
+                        };
+
+                        let prev = take(&mut seq);
+                        let prev_or = take(&mut seq_or);
+                        stack.push((prev, prev_or, kind, outer_flags));
+                    },
                     '(' => {
+                        group += 1;
                         let prev = take(&mut seq);
                         let prev_or = take(&mut seq_or);
-                        stack.push((prev, prev_or));
+                        stack.push((prev, prev_or, Group::Capture(group, None), flags));
                     },
                     ')' => {
-                        if let Some((mut prev, prev_or)) = stack.pop() {
+                        if let Some((mut prev, prev_or, kind, prev_flags)) = stack.pop() {
                             if !seq.is_empty() {
                                 seq_or.push(AST::Seq(seq));
                             }
-                            if let Some(ast) = fold_or(seq_or) {
-                                prev.push(ast);
-                            }
+                            let ast = fold_or(seq_or).unwrap_or(AST::Seq(Vec::new()));
+                            prev.push(match kind {
+                                Group::Capture(n, name) => AST::Capture(n, name, Box::new(ast)),
+                                Group::NonCapture => ast,
+                                Group::LookAround { behind, negated } => AST::LookAround {
+                                    expr: Box::new(ast),
+                                    behind,
+                                    negated,
+                                },
+                                Group::Atomic => AST::Atomic(Box::new(ast)),
+                            });
                             seq = prev;
                             seq_or = prev_or;
+                            flags = prev_flags;
                         } else {
                             return Err(Box::new(ParseError::InvalidRightParen(i)));
                         }
@@ -157,15 +1076,50 @@ pub fn parse(expr: &str) -> Result<AST, Box<ParseError>> {
                             seq_or.push(AST::Seq(prev));
                         }
                     },
+                    '[' => seq.push(parse_class(&mut chars, flags)?),
+                    '.' => seq.push(AST::Any(flags.dot_all)),
+                    '^' if flags.multi_line => seq.push(AST::Assert(Look::StartLine)),
+                    '^' => seq.push(AST::Assert(Look::StartText)),
+                    '$' if flags.multi_line => seq.push(AST::Assert(Look::EndLine)),
+                    '$' => seq.push(AST::Assert(Look::EndText)),
                     '\\' => state = ParseState::Escape,
-                    _ => seq.push(AST::Char(c)),
+                    _ => seq.push(apply_case(AST::Char(c), flags)),
                 }
             },
             ParseState::Escape => {
-                let ast = parse_escape(i, c)?;
-                seq.push(ast);
+                match c {
+                    'Q' if flags.syntax == Syntax::Standard => {
+                        state = ParseState::Quote;
+                        continue;
+                    }
+                    '1'..='9' | 'k' => {
+                        let n = parse_backref(&mut chars, i, c, &names)?;
+                        // 閉じていないグループの前回の反復を参照する後方参照には対応しない
+                        let open = stack
+                            .iter()
+                            .any(|(_, _, kind, _)| matches!(kind, Group::Capture(m, _) if *m == n));
+                        if open {
+                            return Err(Box::new(ParseError::SelfReferencingBackref(i, n)));
+                        }
+                        if c != 'k' {
+                            backrefs.push((i, n));
+                        }
+                        seq.push(AST::Backref(n, flags.case_insensitive));
+                    }
+                    _ => {
+                        let ast = parse_escape(&mut chars, i, c, flags)?;
+                        seq.push(apply_case(ast, flags));
+                    }
+                }
                 state = ParseState::Char;
             }
+            ParseState::Quote => {
+                if c == '\\' && chars.next_if(|(_, c)| *c == 'E').is_some() {
+                    state = ParseState::Char;
+                } else {
+                    seq.push(apply_case(AST::Char(c), flags));
+                }
+            }
         }
     }
 
@@ -173,6 +1127,10 @@ pub fn parse(expr: &str) -> Result<AST, Box<ParseError>> {
         return Err(Box::new(ParseError::NoRightParen));
     }
 
+    if let Some((pos, _)) = backrefs.iter().find(|(_, n)| *n > group) {
+        return Err(Box::new(ParseError::InvalidBackref(*pos)));
+    }
+
     if !seq.is_empty() {
         seq_or.push(AST::Seq(seq));
     }
@@ -191,6 +1149,462 @@ mod tests {
     #[test]
     fn test_parse() {
         let ast = parse("a+").unwrap();
-        assert_eq!(ast, AST::Seq(vec![AST::Plus(Box::new(AST::Char('a')))]));
+        assert_eq!(ast, AST::Seq(vec![AST::Plus(Box::new(AST::Char('a')), true)]));
+
+        let ast = parse("a*?b+?c??d{2,}?").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                AST::Star(Box::new(AST::Char('a')), false),
+                AST::Plus(Box::new(AST::Char('b')), false),
+                AST::Question(Box::new(AST::Char('c')), false),
+                AST::Repeat {
+                    expr: Box::new(AST::Char('d')),
+                    min: 2,
+                    max: None,
+                    greedy: false,
+                },
+            ])
+        );
+
+        let ast = parse("(a)(b(c))").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                AST::Capture(1, None, Box::new(AST::Seq(vec![AST::Char('a')]))),
+                AST::Capture(
+                    2,
+                    None,
+                    Box::new(AST::Seq(vec![
+                        AST::Char('b'),
+                        AST::Capture(3, None, Box::new(AST::Seq(vec![AST::Char('c')]))),
+                    ]))
+                ),
+            ])
+        );
+
+        let ast = parse("[a-z_][^]0-9-]").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                AST::Class(vec![('_', '_'), ('a', 'z')].into(), false),
+                AST::Class(vec![('-', '-'), ('0', '9'), (']', ']')].into(), true),
+            ])
+        );
+
+        let ast = parse(r"[\]\-a-]").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![AST::Class(vec![('-', '-'), (']', ']'), ('a', 'a')].into(), false)])
+        );
+
+        let ast = parse(r"a(.(?s).).(?s).\.").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                AST::Char('a'),
+                AST::Capture(1, None, Box::new(AST::Seq(vec![AST::Any(false), AST::Any(true)]))),
+                AST::Any(false),
+                AST::Any(true),
+                AST::Char('.'),
+            ])
+        );
+        let flags = Flags {
+            dot_all: true,
+            ..Default::default()
+        };
+        let ast = parse_with_flags("(.)", flags).unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![AST::Capture(1, None, Box::new(AST::Seq(vec![AST::Any(true)])))])
+        );
+        assert!(matches!(*parse("(?z)").unwrap_err(), ParseError::UnknownFlag(2, 'z')));
+        assert!(matches!(*parse("(?s-m-i)").unwrap_err(), ParseError::UnknownFlag(5, '-')));
+        assert!(matches!(*parse("(?i").unwrap_err(), ParseError::NoRightParen));
+
+        let ast = parse("(?:a)(?P<x>b)(c)(?<y_1>d)").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                AST::Seq(vec![AST::Char('a')]),
+                AST::Capture(1, Some("x".to_string()), Box::new(AST::Seq(vec![AST::Char('b')]))),
+                AST::Capture(2, None, Box::new(AST::Seq(vec![AST::Char('c')]))),
+                AST::Capture(3, Some("y_1".to_string()), Box::new(AST::Seq(vec![AST::Char('d')]))),
+            ])
+        );
+        assert!(matches!(*parse("a(?P<1x>b)").unwrap_err(), ParseError::InvalidGroupName(1)));
+        assert!(matches!(*parse("(?P<>b)").unwrap_err(), ParseError::InvalidGroupName(0)));
+        assert!(matches!(*parse("(?Px)").unwrap_err(), ParseError::InvalidGroupName(0)));
+        assert!(matches!(*parse("(?<x").unwrap_err(), ParseError::InvalidGroupName(0)));
+        assert!(matches!(
+            *parse("(?<x>a)(?<x>b)").unwrap_err(),
+            ParseError::DuplicateGroupName(7, ref name) if name == "x"
+        ));
+
+        let ast = parse(r"(a)(?<q>b)\2\k<q>\1(c)\3").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                AST::Capture(1, None, Box::new(AST::Seq(vec![AST::Char('a')]))),
+                AST::Capture(2, Some("q".to_string()), Box::new(AST::Seq(vec![AST::Char('b')]))),
+                AST::Backref(2, false),
+                AST::Backref(2, false),
+                AST::Backref(1, false),
+                AST::Capture(3, None, Box::new(AST::Seq(vec![AST::Char('c')]))),
+                AST::Backref(3, false),
+            ])
+        );
+        assert!(matches!(*parse(r"(a)\2").unwrap_err(), ParseError::InvalidBackref(4)));
+        assert!(matches!(*parse(r"\k<q>(?<q>a)").unwrap_err(), ParseError::InvalidBackref(1)));
+        assert!(matches!(*parse(r"(a)\k1").unwrap_err(), ParseError::InvalidBackref(4)));
+        assert!(matches!(
+            *parse(r"(a\1?)+").unwrap_err(),
+            ParseError::SelfReferencingBackref(3, 1)
+        ));
+        assert!(matches!(
+            *parse(r"(?<q>a(b\k<q>))").unwrap_err(),
+            ParseError::SelfReferencingBackref(9, 1)
+        ));
+        assert!(parse(r"((a)\2)\1").is_ok());
+
+        let look = |c, behind, negated| AST::LookAround {
+            expr: Box::new(AST::Seq(vec![AST::Char(c)])),
+            behind,
+            negated,
+        };
+        let ast = parse("(?=a)(?!b)(?<=c)(?<!d)").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                look('a', false, false),
+                look('b', false, true),
+                look('c', true, false),
+                look('d', true, true),
+            ])
+        );
+        assert!(matches!(*parse("(?P=a)").unwrap_err(), ParseError::InvalidGroupName(0)));
+
+        let atomic = |ast| AST::Atomic(Box::new(ast));
+        let ast = parse("(?>a)b*+c++d?+e{2}+f+?").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                atomic(AST::Seq(vec![AST::Char('a')])),
+                atomic(AST::Star(Box::new(AST::Char('b')), true)),
+                atomic(AST::Plus(Box::new(AST::Char('c')), true)),
+                atomic(AST::Question(Box::new(AST::Char('d')), true)),
+                atomic(AST::Repeat {
+                    expr: Box::new(AST::Char('e')),
+                    min: 2,
+                    max: Some(2),
+                    greedy: true,
+                }),
+                AST::Plus(Box::new(AST::Char('f')), false),
+            ])
+        );
+
+        let ast = parse("(?s)(?-s:.(?s).)+.").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                AST::Plus(Box::new(AST::Seq(vec![AST::Any(false), AST::Any(true)])), true),
+                AST::Any(true),
+            ])
+        );
+
+        let ast = parse("(?x) a (b | c) +  # comment\n \\  \\# #").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                AST::Char('a'),
+                AST::Plus(
+                    Box::new(AST::Capture(
+                        1,
+                        None,
+                        Box::new(AST::Or(
+                            Box::new(AST::Seq(vec![AST::Char('b')])),
+                            Box::new(AST::Seq(vec![AST::Char('c')])),
+                        ))
+                    )),
+                    true
+                ),
+                AST::Char(' '),
+                AST::Char('#'),
+            ])
+        );
+
+        let ast = parse(r"^\A(?m)^a$\z\$").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                AST::Assert(Look::StartText),
+                AST::Assert(Look::StartText),
+                AST::Assert(Look::StartLine),
+                AST::Char('a'),
+                AST::Assert(Look::EndLine),
+                AST::Assert(Look::EndText),
+                AST::Char('$'),
+            ])
+        );
+
+        let ast = parse(r"\b\B(?u)\b\B").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                AST::Assert(Look::WordBoundary),
+                AST::Assert(Look::NotWordBoundary),
+                AST::Assert(Look::WordBoundaryUnicode),
+                AST::Assert(Look::NotWordBoundaryUnicode),
+            ])
+        );
+
+        let ast = parse("a{3}b{2,}c{0,4}{x}").unwrap();
+        let repeat = |c, min, max| AST::Repeat {
+            expr: Box::new(AST::Char(c)),
+            min,
+            max,
+            greedy: true,
+        };
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                repeat('a', 3, Some(3)),
+                repeat('b', 2, None),
+                repeat('c', 0, Some(4)),
+                AST::Char('{'),
+                AST::Char('x'),
+                AST::Char('}'),
+            ])
+        );
+        assert!(matches!(*parse("a{3,2}").unwrap_err(), ParseError::InvalidRepeat(1)));
+        assert!(matches!(*parse("a{3x}").unwrap_err(), ParseError::InvalidRepeat(1)));
+        assert!(matches!(*parse("{3}").unwrap_err(), ParseError::NoPrev(0)));
+
+        assert!(matches!(*parse("[a-z").unwrap_err(), ParseError::NoRightBracket));
+        assert!(matches!(*parse("[z-a]").unwrap_err(), ParseError::InvalidRange(1, 'z', 'a')));
+        assert!(matches!(*parse(r"[\q]").unwrap_err(), ParseError::InvalidEscape(2, 'q')));
+
+        let ast = parse(r"\d\W[\s_-]").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                AST::Class(vec![('0', '9')].into(), false),
+                AST::Class(vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')].into(), true),
+                AST::Class(vec![('\t', '\r'), (' ', ' '), ('-', '-'), ('_', '_')].into(), false),
+            ])
+        );
+        let ast = parse(r"\t\x41\u{1F600}\0[\n-\r]").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                AST::Char('\t'),
+                AST::Char('A'),
+                AST::Char('😀'),
+                AST::Char('\0'),
+                AST::Class(vec![('\n', '\r')].into(), false),
+            ])
+        );
+        assert!(matches!(*parse(r"a\x4").unwrap_err(), ParseError::InvalidHex(2)));
+        assert!(matches!(*parse(r"[\xg0]").unwrap_err(), ParseError::InvalidHex(2)));
+        assert!(matches!(*parse(r"\u41").unwrap_err(), ParseError::InvalidHex(1)));
+        assert!(matches!(*parse(r"\u{}").unwrap_err(), ParseError::InvalidHex(1)));
+        assert!(matches!(*parse(r"\u{1234567}").unwrap_err(), ParseError::InvalidHex(1)));
+        assert!(matches!(
+            *parse(r"\u{D800}").unwrap_err(),
+            ParseError::InvalidCodePoint(1, 0xd800)
+        ));
+
+        let ast = parse(r"\pL\P{greek}[\p{Script=Han}a]").unwrap();
+        let mut han = unicode_tables::property("Han").unwrap().to_vec();
+        han.push(('a', 'a'));
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                AST::Class(unicode_tables::property("Letter").unwrap().into(), false),
+                AST::Class(unicode_tables::property("Greek").unwrap().into(), true),
+                AST::Class(normalize_ranges(han).into(), false),
+            ])
+        );
+        assert!(matches!(
+            *parse(r"a\p{Klingon}").unwrap_err(),
+            ParseError::UnknownProperty(2, ref name) if name == "Klingon"
+        ));
+        assert!(matches!(*parse(r"\p{L").unwrap_err(), ParseError::UnknownProperty(1, _)));
+
+        let ast = parse(r"(?u)\d").unwrap();
+        assert_eq!(ast, AST::Seq(vec![AST::Class(PERL_DIGIT.into(), false)]));
+        assert_eq!(
+            negate_ranges(&[('\0', '9')]),
+            vec![(':', char::MAX)]
+        );
+        assert_eq!(
+            negate_ranges(&[('a', 'a'), ('\u{e000}', char::MAX)]),
+            vec![('\0', '`'), ('b', '\u{d7ff}')]
+        );
+
+        let ere = Flags {
+            syntax: Syntax::PosixExtended,
+            ..Default::default()
+        };
+
+        let ast = parse(r"a\Q.*(\\E+\Q[").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                AST::Char('a'),
+                AST::Char('.'),
+                AST::Char('*'),
+                AST::Char('('),
+                AST::Plus(Box::new(AST::Char('\\')), true),
+                AST::Char('['),
+            ])
+        );
+        let text = "a.b*c\\(d)[e]{1}|^$?+ #é";
+        let chars = text.chars().map(AST::Char).collect::<Vec<_>>();
+        assert_eq!(parse(&escape(text)).unwrap(), AST::Seq(chars));
+        assert_eq!(escape("a\tb"), "a\\u{9}b");
+        assert_eq!(parse(&escape("")).unwrap(), AST::Seq(vec![AST::Seq(vec![])]));
+        assert!(matches!(
+            *parse_with_flags(r"\Qa\E", ere).unwrap_err(),
+            ParseError::InvalidEscape(1, 'Q')
+        ));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_parse_case_insensitive() {
+        let ast = parse(r"a(?i)k[^a-c]\x31").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                AST::Char('a'),
+                AST::Class(vec![('K', 'K'), ('k', 'k'), ('\u{212a}', '\u{212a}')].into(), false),
+                AST::Class(vec![('A', 'C'), ('a', 'c')].into(), true),
+                AST::Char('1'),
+            ])
+        );
+        assert_eq!(
+            normalize_ranges(vec![('x', 'z'), ('a', 'c'), ('b', 'd'), ('e', 'e'), ('g', 'g')]),
+            vec![('a', 'e'), ('g', 'g'), ('x', 'z')]
+        );
+
+        // 集合演算の被演算子と否定される要素は、演算の前に同一視される文字を含める
+        let ast = parse("(?i)[a-c--B]").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![AST::Class(
+                vec![('A', 'A'), ('C', 'C'), ('a', 'a'), ('c', 'c')].into(),
+                false
+            )])
+        );
+        let ast = parse(r"(?i)[\W]").unwrap();
+        let word = fold_ranges(vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')]);
+        assert_eq!(ast, AST::Seq(vec![AST::Class(negate_ranges(&word).into(), false)]));
+    }
+
+    #[test]
+    fn test_parse_class_set_operation() {
+        let ast = parse("[a-z--[aeiou]][[a-f]&&[d-k]x][a--c]").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                AST::Class(
+                    vec![('b', 'd'), ('f', 'h'), ('j', 'n'), ('p', 't'), ('v', 'z')].into(),
+                    false
+                ),
+                AST::Class(vec![('d', 'f')].into(), false),
+                AST::Class(vec![('a', 'a')].into(), false),
+            ])
+        );
+        let ast = parse(r"[^\w&&[^\d]&&[a-z_]--_]").unwrap();
+        assert_eq!(ast, AST::Seq(vec![AST::Class(vec![('a', 'z')].into(), true)]));
+        assert!(matches!(*parse("[&&a]").unwrap_err(), ParseError::EmptyClassOperand(1)));
+        assert!(matches!(*parse("[a&&[b]").unwrap_err(), ParseError::NoRightBracket));
+
+        // POSIXの構文では`[`は入れ子のクラスにならない
+        let ere = Flags {
+            syntax: Syntax::PosixExtended,
+            ..Default::default()
+        };
+        let ast = parse_with_flags("[[a-]", ere).unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![AST::Class(vec![('-', '-'), ('[', '['), ('a', 'a')].into(), false)])
+        );
+        assert_eq!(
+            intersect_ranges(&[('a', 'c'), ('x', 'z')], &[('b', 'y')]),
+            vec![('b', 'c'), ('x', 'y')]
+        );
+
+        // 入れ子の否定は、同一視される文字を含めてから補集合をとる
+        let ast = parse("(?i)[^[^k]]").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![AST::Class(
+                negate_ranges(&[('K', 'K'), ('k', 'k'), ('\u{212a}', '\u{212a}')]).into(),
+                true
+            )])
+        );
+    }
+
+    #[test]
+    fn test_parse_posix() {
+        let ast = parse("[[:xdigit:]-]").unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![AST::Class(
+                vec![('-', '-'), ('0', '9'), ('A', 'F'), ('a', 'f')].into(),
+                false
+            )])
+        );
+        assert!(matches!(
+            *parse("a[[:alfa:]]").unwrap_err(),
+            ParseError::UnknownPosixClass(2, ref name) if name == "alfa"
+        ));
+        assert!(matches!(*parse("[[:alpha]").unwrap_err(), ParseError::UnknownPosixClass(1, _)));
+
+        let bre = Flags {
+            syntax: Syntax::PosixBasic,
+            ..Default::default()
+        };
+        let ast = parse_with_flags(r"\(a*\)\{2\}?", bre).unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                AST::Repeat {
+                    expr: Box::new(AST::Capture(
+                        1,
+                        None,
+                        Box::new(AST::Seq(vec![AST::Star(Box::new(AST::Char('a')), true)]))
+                    )),
+                    min: 2,
+                    max: Some(2),
+                    greedy: true,
+                },
+                AST::Char('?'),
+            ])
+        );
+        assert!(matches!(
+            *parse_with_flags(r"a\)", bre).unwrap_err(),
+            ParseError::InvalidRightParen(1)
+        ));
+
+        // 括弧式の中の`\`は通常の文字
+        let ere = Flags {
+            syntax: Syntax::PosixExtended,
+            ..Default::default()
+        };
+        let class = || AST::Seq(vec![AST::Class(vec![('\\', '\\'), ('n', 'n')].into(), false)]);
+        assert_eq!(parse_with_flags(r"[\n]", ere).unwrap(), class());
+        assert_eq!(parse_with_flags(r"[\n]", bre).unwrap(), class());
+        let ast = parse_with_flags(r"a[\]b", bre).unwrap();
+        assert_eq!(
+            ast,
+            AST::Seq(vec![
+                AST::Char('a'),
+                AST::Class(vec![('\\', '\\')].into(), false),
+                AST::Char('b'),
+            ])
+        );
+        assert!(matches!(*parse(r"a[\]b").unwrap_err(), ParseError::NoRightBracket));
+    }
+}
@@ -6,10 +6,23 @@
 //! use reg;
 //! let expr = "a(bc)+|c(def)*"; // 正規表現
 //! let line = "cdefdefdef"; // マッチ対象文字列
-//! reg::do_matching(expr, line, true); // 幅優先探索でマッチング
+//! reg::do_matching(expr, line, false); // 幅優先探索でマッチング
 //! reg::print(expr); // 正規表現のASTと命令列を表示
 //! ```
+//!
+//! 同じ正規表現で何度もマッチングする場合は、`Regex`で一度だけコンパイルする。
+//!
+//! ```
+//! use reg::Regex;
+//! let re = Regex::new("a(bc)+|c(def)*").unwrap();
+//! assert!(re.is_match("xxcdefdef").unwrap());
+//! assert!(!re.is_match("xyz").unwrap());
+//! ```
 mod engine;
 mod helper;
 
-pub use engine::{do_matching, print};
\ No newline at end of file
+pub use engine::{
+    do_matching, escape, print, CaptureNames, Captures, Engine, Match, Matches, Regex,
+    RegexBuilder, Syntax,
+};
+pub use helper::DynError;
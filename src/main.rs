@@ -1,10 +1,7 @@
 use std::env;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
-use crate::helper::DynError;
-
-mod engine;
-mod helper;
+use reg::{DynError, Regex};
 
 fn main() -> Result<(), DynError> {
     let args: Vec<String> = env::args().collect();
@@ -22,16 +19,14 @@ fn match_file(expr: &str, file: &str) -> Result<(), DynError> {
     let f = File::open(file)?;
     let reader = BufReader::new(f);
 
-    engine::print(expr)?;
+    reg::print(expr)?;
     println!();
 
+    let re = Regex::new(expr)?;
     for line in reader.lines() {
         let line = line?;
-        for (i, _) in line.char_indices() {
-            if engine::do_matching(expr, &line[i..], true)? {
-                println!("{line}");
-                break;
-            }
+        if re.is_match(&line)? {
+            println!("{line}");
         }
     }
 